@@ -0,0 +1,276 @@
+//!
+//! ## Audit log
+//!
+//! Biometric events are reported to an `AuditSink` installed with
+//! `BmLite::set_audit()`, stamped with the time from a clock function
+//! supplied by the application. Events are recorded once the sensor has
+//! confirmed the operation, failed link traffic is not an event.
+//!
+//! `RingLog` keeps the latest records in RAM, no heap needed. With the `std` feature
+//! `FileLog` appends them as text lines to a file. To read a sink back
+//! after handing it to the driver, share it as `Rc<RefCell<_>>`.
+//! Without `alloc` the driver takes a `&'static mut` sink instead.
+//!
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use alloc::rc::Rc;
+#[cfg(feature = "alloc")]
+use core::cell::RefCell;
+
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use {BmLite, SensorSpi};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditEvent {
+    /// New template saved by enrollment
+    Enrolled {
+        id: u16,
+    },
+    /// Enrollment refused, finger already stored as `existing_id`
+    EnrollRejected {
+        existing_id: u32,
+    },
+    /// Template saved from a backup or sealed export
+    Imported {
+        id: u16,
+    },
+    Identified {
+        id: u32,
+    },
+    /// Identify found no matching template
+    IdentifyFailed,
+    Deleted {
+        id: u16,
+    },
+    DeletedAll,
+    ConfigChanged(ConfigChange),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigChange {
+    RetryPolicy,
+    LinkConfig,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AuditRecord {
+    /// Time from the clock given to `set_audit()`
+    pub time: u64,
+    pub event: AuditEvent,
+}
+
+/// Destination for audit records
+pub trait AuditSink {
+    fn record(&mut self, record: &AuditRecord);
+}
+
+#[cfg(feature = "alloc")]
+impl<S: AuditSink> AuditSink for Rc<RefCell<S>> {
+    fn record(&mut self, record: &AuditRecord) {
+        self.borrow_mut().record(record)
+    }
+}
+
+/// Latest `N` records in RAM, the oldest are dropped first
+#[derive(Clone, Debug)]
+pub struct RingLog<const N: usize> {
+    records: [Option<AuditRecord>; N],
+    // Index of the oldest record
+    head: usize,
+    len: usize,
+    dropped: u32,
+}
+
+impl<const N: usize> RingLog<N> {
+    pub fn new() -> Self {
+        RingLog {
+            records: [None; N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Records oldest first
+    pub fn records(&self) -> impl Iterator<Item = &AuditRecord> {
+        (0..self.len).filter_map(move |i| self.records[(self.head + i) % N].as_ref())
+    }
+
+    /// Records dropped to make room since the log was created
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Take records out of the log oldest first, the ones not iterated
+    /// stay in the log
+    pub fn drain(&mut self) -> impl Iterator<Item = AuditRecord> + '_ {
+        core::iter::from_fn(move || {
+            if self.len == 0 {
+                return None;
+            }
+            let record = self.records[self.head].take();
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+            record
+        })
+    }
+}
+
+impl<const N: usize> Default for RingLog<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> AuditSink for RingLog<N> {
+    fn record(&mut self, record: &AuditRecord) {
+        if N == 0 {
+            self.dropped = self.dropped.saturating_add(1);
+            return;
+        }
+        if self.len == N {
+            // Oldest slot becomes the newest
+            self.records[self.head] = Some(*record);
+            self.head = (self.head + 1) % N;
+            self.dropped = self.dropped.saturating_add(1);
+        } else {
+            self.records[(self.head + self.len) % N] = Some(*record);
+            self.len += 1;
+        }
+    }
+}
+
+/// Text lines `<time> <event>` appended to a file or other writer
+#[cfg(feature = "std")]
+pub struct FileLog<W = ::std::fs::File> {
+    out: W,
+    error: Option<::std::io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl FileLog<::std::fs::File> {
+    /// Append to the file at `path`, creating it if needed
+    pub fn open<P: AsRef<::std::path::Path>>(path: P) -> ::std::io::Result<Self> {
+        let file = ::std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(FileLog::new(file))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: ::std::io::Write> FileLog<W> {
+    pub fn new(out: W) -> Self {
+        FileLog { out, error: None }
+    }
+
+    /// First write error since last asked, records after it may be lost
+    pub fn take_error(&mut self) -> Option<::std::io::Error> {
+        self.error.take()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: ::std::io::Write> AuditSink for FileLog<W> {
+    fn record(&mut self, record: &AuditRecord) {
+        let written =
+            writeln!(self.out, "{} {:?}", record.time, record.event).and_then(|_| self.out.flush());
+        if let Err(e) = written {
+            if self.error.is_none() {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+type Sink = Box<dyn AuditSink>;
+#[cfg(not(feature = "alloc"))]
+type Sink = &'static mut dyn AuditSink;
+
+/// Installed sink and its clock
+pub(crate) struct Audit {
+    sink: Sink,
+    clock: fn() -> u64,
+}
+
+impl<DEV, RST, IRQ> BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    /// Report biometric events to `sink`, stamped with the time `clock`
+    /// returns. Replaces any sink set before.
+    #[cfg(feature = "alloc")]
+    pub fn set_audit(&mut self, sink: Box<dyn AuditSink>, clock: fn() -> u64) {
+        self.audit = Some(Audit { sink, clock });
+    }
+
+    /// Report biometric events to `sink`, stamped with the time `clock`
+    /// returns. Replaces any sink set before.
+    #[cfg(not(feature = "alloc"))]
+    pub fn set_audit(&mut self, sink: &'static mut dyn AuditSink, clock: fn() -> u64) {
+        self.audit = Some(Audit { sink, clock });
+    }
+
+    /// Stop auditing and return the sink
+    #[cfg(feature = "alloc")]
+    pub fn take_audit(&mut self) -> Option<Box<dyn AuditSink>> {
+        self.audit.take().map(|a| a.sink)
+    }
+
+    /// Stop auditing and return the sink
+    #[cfg(not(feature = "alloc"))]
+    pub fn take_audit(&mut self) -> Option<&'static mut dyn AuditSink> {
+        self.audit.take().map(|a| a.sink)
+    }
+
+    pub(crate) fn audit(&mut self, event: AuditEvent) {
+        if let Some(ref mut audit) = self.audit {
+            let record = AuditRecord {
+                time: (audit.clock)(),
+                event,
+            };
+            audit.sink.record(&record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use self::std::vec::Vec;
+    use super::*;
+
+    fn record(time: u64) -> AuditRecord {
+        AuditRecord {
+            time,
+            event: AuditEvent::Deleted { id: time as u16 },
+        }
+    }
+
+    #[test]
+    fn ring_log_keeps_latest() {
+        let mut log: RingLog<3> = RingLog::new();
+        for t in 0..5 {
+            log.record(&record(t));
+        }
+        let times: Vec<u64> = log.records().map(|r| r.time).collect();
+        assert_eq!(times, [2, 3, 4].to_vec());
+        assert_eq!(log.dropped(), 2);
+        assert_eq!(log.drain().next().map(|r| r.time), Some(2));
+        log.record(&record(5));
+        let times: Vec<u64> = log.drain().map(|r| r.time).collect();
+        assert_eq!(times, [3, 4, 5].to_vec());
+        assert_eq!(log.records().count(), 0);
+    }
+}
@@ -4,65 +4,123 @@
 //!
 //!
 
-#![feature(unsize)]
 #![no_std]
-#![feature(alloc)]
-// Plug in the allocator crate
+// With alloc the application provides the global allocator, on Cortex-M
+// the cortex-m-alloc feature re-exports one
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
 use alloc::vec::Vec;
 
 extern crate crc;
 extern crate embedded_hal;
+extern crate embedded_hal_02;
 use crc::crc32;
 extern crate byteorder;
 
 extern crate nb;
 
-use embedded_hal::blocking::spi::Transfer;
-use embedded_hal::digital::{InputPin, OutputPin};
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "defmt")]
+extern crate defmt;
+#[cfg(feature = "log")]
+extern crate log;
+
+#[cfg(feature = "cortex-m-alloc")]
+extern crate alloc_cortex_m;
+#[cfg(feature = "cortex-m-alloc")]
+pub use alloc_cortex_m::CortexMHeap;
+
+#[cfg(feature = "sealed")]
+extern crate chacha20poly1305;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{self, InputPin, OutputPin};
+use embedded_hal_02::blocking::spi::Transfer as Transfer02;
+use embedded_hal_02::digital::v2::{InputPin as InputPin02, OutputPin as OutputPin02};
 
 use byteorder::{ByteOrder, LittleEndian};
 
+mod compat;
+pub use compat::Compat;
+mod device;
+pub use device::MAX_CHUNKS;
+pub use device::{DeviceError, ExclusiveDevice, NoDelay, SensorSpi, SharedDevice};
+mod dma;
+pub use dma::{DmaDevice, DmaDone, DmaError, DmaTransfer};
+mod buffer;
+pub use buffer::{ArrayBuf, Bytes, BUF_SIZE};
+mod audit;
+use audit::Audit;
+#[cfg(feature = "std")]
+pub use audit::FileLog;
+pub use audit::{AuditEvent, AuditRecord, AuditSink, ConfigChange, RingLog};
+mod enroll;
+pub use enroll::{AddImage, AddImageResult, Collecting, Complete, EnrollSession};
+mod irq;
+pub use irq::{Completion, FingerWait};
+mod trace;
+pub use trace::{Args, TraceEvent};
+mod lockout;
+pub use lockout::{Lockout, LockoutPolicy, LockoutState, LockoutStore};
+mod sensor;
+pub use sensor::FingerprintSensor;
+#[cfg(feature = "alloc")]
+pub use sensor::{FakeError, FakeSensor};
+#[cfg(feature = "registry")]
+mod registry;
+#[cfg(feature = "registry")]
+pub use registry::{RegistryError, UserRecord, UserRegistry};
+#[cfg(feature = "vault")]
+mod vault;
+#[cfg(feature = "vault")]
+pub use vault::{Vault, VaultDiff, VaultEntry, VaultError, VAULT_VERSION};
+#[cfg(feature = "sealed")]
+mod sealed;
+#[cfg(feature = "sealed")]
+pub use sealed::{SealError, SealedTemplate, SEALED_VERSION};
+
 // Buffer type for sending data to packages to BM Lite
 //
 trait TransportBuffer<Output> {
     fn create_transport_buffer() -> Output;
-    fn push_crc(self) -> Self;
-    fn set_cmd(self, u16) -> Self;
+    fn set_cmd(self, cmd: u16) -> Self;
     fn get_cmd(&self) -> Option<u16>;
-    fn push_u16(self, u16) -> Self;
-    fn push_u32(self, u32) -> Self;
-    fn add_arg(self, u16) -> Self;
-    fn add_arg_u8(self, u16, u8) -> Self;
-    fn add_arg_u16(self, u16, u16) -> Self;
-    fn add_arg_u32(self, u16, u32) -> Self;
+    fn push_u16(self, data: u16) -> Self;
+    fn push_u32(self, data: u32) -> Self;
+    fn add_arg(self, arg: u16) -> Self;
+    #[cfg(test)]
+    fn add_arg_u8(self, arg: u16, data: u8) -> Self;
+    fn add_arg_u16(self, arg: u16, data: u16) -> Self;
+    fn add_arg_u32(self, arg: u16, data: u32) -> Self;
+    #[cfg(any(feature = "alloc", test))]
+    fn add_arg_data(self, arg: u16, data: &[u8]) -> Self;
 }
 
-impl TransportBuffer<Vec<u8>> for Vec<u8> {
-    fn create_transport_buffer() -> Vec<u8> {
-        let mut v = Vec::with_capacity(256);
-        v.extend(&[1, 0, 0, 0, 0x0, 0x00, 0x01, 0x00, 0x01, 0]);
+impl TransportBuffer<Bytes> for Bytes {
+    fn create_transport_buffer() -> Bytes {
+        let mut v = Bytes::with_capacity(256);
+        v.extend_from_slice(&[1, 0, 0, 0, 0x0, 0x00, 0x01, 0x00, 0x01, 0]);
         v
     }
     fn get_cmd(&self) -> Option<u16> {
         if self.len() >= 12 {
-            let resp = (LittleEndian::read_uint(&self[11..12], 2) & 0xFFFF) as u16;
-            return Some(resp);
+            return Some(LittleEndian::read_u16(&self[10..12]));
         }
         None
     }
     fn set_cmd(self, cmd: u16) -> Self {
         if self.len() != 10 {
-            assert!(false, "unexpected command added");
+            panic!("unexpected command added");
             //self.push or correct code
         }
         self.push_u16(cmd).push_u16(0)
     }
-    fn push_crc(self) -> Self {
-        let crc = crc::crc32::checksum_ieee(&self[4..]);
-        self.push_u32(crc)
-    }
 
     fn push_u16(mut self, data: u16) -> Self {
         self.push((0xFF & data) as u8);
@@ -84,9 +142,10 @@ impl TransportBuffer<Vec<u8>> for Vec<u8> {
         self[12] += 1;
         self.push_u16(arg).push_u16(0)
     }
+    #[cfg(test)]
     fn add_arg_u8(mut self, arg: u16, data: u8) -> Self {
         self[12] += 1;
-        let mut s = self.push_u16(arg).push_u16(2);
+        let mut s = self.push_u16(arg).push_u16(1);
         s.push(data);
         s
     }
@@ -98,69 +157,265 @@ impl TransportBuffer<Vec<u8>> for Vec<u8> {
         self[12] += 1;
         self.push_u16(arg).push_u16(4).push_u32(data)
     }
+    #[cfg(any(feature = "alloc", test))]
+    fn add_arg_data(mut self, arg: u16, data: &[u8]) -> Self {
+        self[12] += 1;
+        let mut s = self.push_u16(arg).push_u16(data.len() as u16);
+        s.extend_from_slice(data);
+        s
+    }
 }
 // Buffer type for reading from sensor
 trait LinkBuffer {
-    fn parse_result<Closure, E>(&self, u16, f: Closure) -> Result<(), Error<E>>
+    fn parse_result<'a, Closure, E>(&'a self, cmd: u16, f: Closure) -> Result<(), Error<E>>
     where
-        Closure: FnMut(u16, &[u8], usize);
+        Closure: FnMut(u16, &'a [u8], usize);
 }
 
-impl LinkBuffer for Vec<u8> {
-    fn parse_result<Closure, E>(&self, cmd: u16, mut callback: Closure) -> Result<(), Error<E>>
+impl LinkBuffer for [u8] {
+    fn parse_result<'a, Closure, E>(
+        &'a self,
+        cmd: u16,
+        mut callback: Closure,
+    ) -> Result<(), Error<E>>
     where
-        Closure: FnMut(u16, &[u8], usize),
+        Closure: FnMut(u16, &'a [u8], usize),
     {
         // Parse result args
         let len = self.len();
-        if len < 6 {
+        if len < 4 {
             // expect at lease some data here
             return Err(Error::UnexpectedResponse);
         }
-        if cmd != as_u16(self[1], self[0]) {
+        let received = as_u16(self[1], self[0]);
+        if cmd != received {
             // command response did not match command.
-            return Err(Error::UnexpectedResponse);
+            return Err(Error::Frame(FrameError::Command {
+                sent: cmd,
+                received,
+            }));
         }
         let argc = as_u16(self[3], self[2]);
         let mut current: usize = 4;
 
-        for _i in 0..argc {
+        for i in 0..argc {
             if len < current + 4 {
                 // Parse error
-                return Err(Error::UnexpectedResponse);
+                return Err(Error::Frame(FrameError::Argument(i)));
             }
             let arg = as_u16(self[1 + current], self[current]);
             let arglen = as_u16(self[3 + current], self[2 + current]) as usize;
             current += 4;
             if len < current + arglen {
                 // Parse error
-                return Err(Error::UnexpectedResponse);
+                return Err(Error::Frame(FrameError::Argument(i)));
             }
-            callback(arg, &self[current..current + arglen], arglen as usize);
+            callback(arg, &self[current..current + arglen], arglen);
             current += arglen;
         }
         Ok(())
     }
 }
 /// BM Lite interface class
-/// spi interface implementing transfer trait
-/// cs,rst and irq gpio pins
+/// spi device handling chip select, see `SensorSpi`
+/// rst and irq gpio pins
 ///
-pub struct BmLite<SPI, CS, RST, IRQ> {
-    spi: SPI,
-    cs: CS,
+pub struct BmLite<DEV, RST, IRQ> {
+    dev: DEV,
     rst: RST,
     irq: IRQ,
+    power: PowerState,
+    retry: RetryPolicy,
+    link: LinkConfig,
+    link_failures: u8,
+    /// Response package of the last command
+    rx: Bytes,
+    /// Sequence number of the response frame read next
+    rx_frame: u16,
+    /// Times the response frame read next was NACKed
+    rx_rerequests: u8,
+    pending: Option<FingerWait>,
+    idle: Option<IdleHook>,
+    delay: Option<Delay>,
+    #[cfg(feature = "trace")]
+    trace: Option<fn(&TraceEvent)>,
+    audit: Option<Audit>,
 }
 
+#[cfg(feature = "alloc")]
+type Delay = Box<dyn DelayNs>;
+#[cfg(not(feature = "alloc"))]
+type Delay = &'static mut dyn DelayNs;
+
+#[cfg(feature = "alloc")]
+type IdleHook = Box<dyn FnMut() -> IdleAction>;
+#[cfg(not(feature = "alloc"))]
+type IdleHook = &'static mut dyn FnMut() -> IdleAction;
+
+/// `BmLite` as built by `BmLite::new()` from an embedded-hal 0.2 SPI bus
+/// and chip select, reset and IRQ pins. Same parameters as `BmLite` had
+/// before it took a `SensorSpi` device.
+pub type SpiBmLite<SPI, CS, RST, IRQ> = BmLite<ExclusiveDevice<SPI, CS>, Compat<RST>, Compat<IRQ>>;
+
 pub enum Error<E> {
     UnexpectedResponse,
     Timeout,
     CRCError,
     NoMatch,
+    /// Sensor NACKed the command
+    Nack,
+    /// Neither ACK nor NACK was read back, most likely line noise
+    BadAck([u8; 4]),
+    /// Module is in deep sleep and only a reset will wake it
+    Sleeping,
+    /// Finger is already enrolled under `existing_id`
+    Duplicate {
+        existing_id: u32,
+    },
+    /// Finger wait started with `start_capture()` or `start_finger_up()`
+    /// has not completed
+    Busy,
+    /// Idle hook asked to stop waiting for the sensor
+    Cancelled,
+    /// `LinkConfig` pauses between chunks on a device that cannot pause,
+    /// or a `RetryPolicy` with hardware reset is set before `set_delay()`
+    InvalidConfig,
+    /// Package does not fit in `Bytes`, only without the `alloc` feature
+    BufferFull,
+    /// Too many failed identifies, try again in `remaining` ms
+    LockedOut {
+        remaining: u64,
+    },
+    /// Response frame failed validation
+    Frame(FrameError),
+    /// Host side user registry refused the operation
+    #[cfg(feature = "registry")]
+    Registry(RegistryError),
+    /// Vault archive cannot be written or restored
+    #[cfg(feature = "vault")]
+    Vault(VaultError),
+    /// Sealed template could not be created or opened
+    #[cfg(feature = "sealed")]
+    Sealed(SealError),
+    /// Reset or IRQ pin could not be set or read
+    Pin(digital::ErrorKind),
     HalErr(E),
 }
 
+fn pin_error<E, P: digital::Error>(e: P) -> Error<E> {
+    Error::Pin(e.kind())
+}
+
+/// Framing problems found when validating a response
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameError {
+    /// Link header names a channel we do not talk on
+    Channel(u16),
+    /// Link size cannot hold a transport frame or is larger than accepted
+    LinkSize(u16),
+    /// Transport size does not agree with the link size
+    TransportSize { link: u16, transport: u16 },
+    /// Sequence number out of range or a multi frame response
+    Sequence { nr: u16, len: u16 },
+    /// Response is for another command than the one sent
+    Command { sent: u16, received: u16 },
+    /// Argument at this index, counted from 0, runs past the end of the
+    /// package
+    Argument(u16),
+}
+
+/// Link layer recovery on CRC and framing errors
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Times a command is resent when the sensor does not ACK it
+    pub resend: u8,
+    /// Times a response is NACKed and read again on CRC error
+    pub rerequest: u8,
+    /// Consecutive failed commands before the link is resynced
+    pub resync_after: u8,
+    /// Resync by pulsing the reset pin instead of draining the sensor,
+    /// needs a delay from `set_delay()`
+    pub hardware_reset: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            resend: 2,
+            rerequest: 2,
+            resync_after: 3,
+            hardware_reset: false,
+        }
+    }
+}
+
+/// How frames are clocked over the SPI bus
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConfig {
+    /// Largest single SPI transfer in bytes, 0 for no limit. Frames are
+    /// split in chunks of this size with chip select held asserted.
+    pub chunk_size: usize,
+    /// Pause between the chunks of a frame, ns
+    pub chunk_pause_ns: u32,
+}
+
+/// What the idle hook wants the driver to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdleAction {
+    /// Keep waiting for the sensor
+    Continue,
+    /// Stop waiting and return `Error::Cancelled`
+    Cancel,
+}
+
+/// Progress of an enrollment, reported to the `enroll()` callback
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnrollEvent {
+    /// Sensor has started a new template
+    Started,
+    /// Waiting for a finger on the sensor
+    PlaceFinger,
+    /// Waiting for the finger to be lifted
+    LiftFinger,
+    /// Image added to the template, `remaining` more are needed
+    ImageAccepted { remaining: u32 },
+    /// Image was not used, `reason` is the sensor result code
+    ImageRejected { reason: u32 },
+    /// Template is complete and being saved
+    Saving,
+    /// Template stored under `id`
+    Done { id: u32 },
+}
+
+/// Options for `enroll_with()`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnrollOptions {
+    /// Identify the finished template against storage and refuse to save
+    /// a finger that is already enrolled
+    pub reject_duplicates: bool,
+}
+
+/// How the module signalled that it was ready after reset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootState {
+    /// Module raised IRQ when boot completed
+    IrqReady,
+    /// No IRQ was seen but the module answered a version query
+    VersionReady,
+}
+
+/// Power state of the BM Lite module as last commanded by the driver
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerState {
+    /// Awake and accepting commands
+    Active,
+    /// Low power, a finger on the sensor wakes the module and raises IRQ.
+    /// Sending any command also wakes it.
+    Sleep,
+    /// Lowest power, finger detect is off and only reset wakes the module
+    DeepSleep,
+}
+
 const ARG_RESULT: u16 = 0x2001;
 const ARG_COUNT: u16 = 0x2002;
 const _ARG_TIMEOUT: u16 = 0x5001;
@@ -168,12 +423,141 @@ const ARG_VERSION: u16 = 0x6003;
 const ARG_GET: u16 = 0x1004;
 const ARG_MATCH: u16 = 0x000A;
 const ARG_ID: u16 = 0x0006;
+#[cfg(feature = "alloc")]
+const ARG_UPLOAD: u16 = 0x1005;
+#[cfg(feature = "alloc")]
+const ARG_DOWNLOAD: u16 = 0x1006;
+const ARG_DATA: u16 = 0x100A;
+const ARG_SLEEP: u16 = 0x4002;
+const ARG_DEEP_SLEEP: u16 = 0x4003;
+
+const CMD_MCU: u16 = 0x5002;
+const CMD_CAPTURE: u16 = 0x0001;
+const CMD_WAIT_FINGER_UP: u16 = 0x0007;
+
+const HCP_CHANNEL: u16 = 0x0001;
+/// Transport header and at least one byte of payload
+const MIN_LINK_SIZE: u16 = 6 + 1;
+const MAX_LINK_SIZE: u16 = 1024;
+/// Application bytes sent in each outgoing transport frame
+const TX_CHUNK: usize = 256;
+/// Link and transport headers and CRC around the payload of a frame
+const FRAME_OVERHEAD: usize = 14;
+
+const ACK: [u8; 4] = [0x7f, 0xff, 0x01, 0x7f];
+const NACK: [u8; 4] = [0x7f, 0xff, 0x00, 0x7f];
+
+/// Time the reset pin is held low
+const RESET_HOLD_MS: u32 = 10;
+/// Time the module is given to raise IRQ after reset
+const BOOT_TIMEOUT_MS: u32 = 100;
 
 fn as_u16(h: u8, l: u8) -> u16 {
     ((h as u16) << 8) | (l as u16)
 }
 
-////  ## Usage
+/// Lowest template id not in `ids`, sensor ids start at 1
+pub(crate) fn lowest_free_id<I>(ids: I) -> Option<u16>
+where
+    I: Iterator<Item = u16> + Clone,
+{
+    (1..=u16::MAX).find(|id| ids.clone().all(|t| t != *id))
+}
+
+// Timeout in ms but 0 waits forever
+fn capture_command(timeout: u32) -> Bytes {
+    let mut transport =
+        <Bytes as TransportBuffer<Bytes>>::create_transport_buffer().set_cmd(CMD_CAPTURE);
+    if timeout != 0 {
+        transport = transport.add_arg_u32(0x5001, timeout);
+    }
+    transport
+}
+
+fn finger_up_command(timeout: u32) -> Bytes {
+    let mut transport =
+        <Bytes as TransportBuffer<Bytes>>::create_transport_buffer().set_cmd(CMD_WAIT_FINGER_UP);
+    if timeout != 0 {
+        transport = transport.add_arg_u32(0x5001, timeout);
+    }
+    transport.add_arg(0x0002) //0002 Enroll
+}
+
+/// Result code of a capture or finger up response
+fn finger_result<E>(resp: &[u8], cmd: u16) -> Result<u8, Error<E>> {
+    let mut result = 0;
+    let mut ok_resp = false;
+    resp.parse_result(cmd, |arg, argv, arglen| {
+        match arg {
+            ARG_RESULT => {
+                ok_resp = true;
+                result = (LittleEndian::read_uint(argv, arglen) & 0xFFFF_FFFF) as u32;
+            }
+            _other => {} // For args we do not care about
+        }
+    })?;
+    if ok_resp {
+        return Ok(result as _);
+    }
+    Err(Error::UnexpectedResponse)
+}
+
+/// One transfer on `dev` framed by chip select, split as `link` says.
+/// A frame cannot be stopped half way, `idle` is called to let the
+/// application run while the device waits and a cancel is left to the
+/// next wait for IRQ.
+fn transfer<DEV, E>(
+    dev: &mut DEV,
+    link: &LinkConfig,
+    idle: &mut Option<IdleHook>,
+    buf: &mut [u8],
+) -> Result<(), Error<E>>
+where
+    DEV: SensorSpi<Error = E>,
+{
+    dev.transaction_with_idle(
+        buf,
+        link.chunk_size,
+        link.chunk_pause_ns,
+        &mut idle_call(idle),
+    )
+    .map_err(Error::HalErr)
+}
+
+/// Idle hook as a device calls it while a transfer runs
+fn idle_call(idle: &mut Option<IdleHook>) -> impl FnMut() + '_ {
+    move || {
+        if let Some(ref mut idle) = *idle {
+            idle();
+        }
+    }
+}
+
+/// Where a frame is built or read in place
+#[derive(Clone, Copy)]
+enum FrameAt {
+    /// Start of the device's frame buffer, moved without a copy
+    Device,
+    /// `rx` from this offset
+    Rx(usize),
+}
+
+/// Wrap one chunk of an application package in a transport frame,
+/// sequence number `nr` of `len`, written to the first
+/// `chunk.len() + FRAME_OVERHEAD` bytes of `frame`
+fn transport_frame(frame: &mut [u8], chunk: &[u8], nr: u16, len: u16) {
+    let end = chunk.len() + 10;
+    LittleEndian::write_u16(&mut frame[0..2], HCP_CHANNEL);
+    LittleEndian::write_u16(&mut frame[2..4], chunk.len() as u16 + 6);
+    LittleEndian::write_u16(&mut frame[4..6], chunk.len() as u16);
+    LittleEndian::write_u16(&mut frame[6..8], nr);
+    LittleEndian::write_u16(&mut frame[8..10], len);
+    frame[10..end].copy_from_slice(chunk);
+    let crc = crc32::checksum_ieee(&frame[4..end]);
+    LittleEndian::write_u32(&mut frame[end..end + 4], crc);
+}
+
+/// ## Usage
 ///```
 /// extern crate bmlite;
 /// # extern crate embedded_hal_mock;
@@ -208,135 +592,603 @@ fn as_u16(h: u8, l: u8) -> u16 {
 ///    let ans = bm.identify();
 ///    match ans {
 ///       // Not identified
-///       Err(_) => {assert!(false, "Function returned unexpected error")}
+///       Err(_) => {panic!("Function returned unexpected error")}
 ///       // User identiy is returned in Ok
 ///       Ok(x) => { assert!(x==1)}
 ///    }       
 ///    #    let (mut spi, (_cs,_b,_c)) = bm.teardown();
 ///    #    spi.done();
 /// ```
-impl<SPI, CS, RST, IRQ, E> BmLite<SPI, CS, RST, IRQ>
+impl<SPI, CS, RST, IRQ, E> BmLite<ExclusiveDevice<SPI, CS>, Compat<RST>, Compat<IRQ>>
 where
-    SPI: Transfer<u8, Error = E>,
-    CS: OutputPin,
-    RST: OutputPin,
-    IRQ: InputPin,
+    SPI: Transfer02<u8, Error = E>,
+    CS: OutputPin02,
+    RST: OutputPin02,
+    IRQ: InputPin02,
 {
-    /// Creates a new driver from an SPI peripheral and a chip select
-    /// digital I/O pin.
+    /// Creates a new driver from an embedded-hal 0.2 SPI peripheral and
+    /// chip select, reset and IRQ digital I/O pins.
     pub fn new(spi: SPI, cs: CS, rst: RST, irq: IRQ) -> Self {
-        let en = BmLite {
-            spi: spi,
-            cs: cs,
-            rst: rst,
-            irq: irq,
-        };
-
-        en
+        BmLite::with_device(
+            ExclusiveDevice::new(spi, cs),
+            Compat::new(rst),
+            Compat::new(irq),
+        )
     }
 
     pub fn teardown(self) -> (SPI, (CS, RST, IRQ)) {
         // Return interfaces
-        (self.spi, (self.cs, self.rst, self.irq))
+        let (dev, rst, irq) = self.release();
+        let (spi, cs) = dev.release();
+        (spi, (cs, rst.into_inner(), irq.into_inner()))
+    }
+}
+
+impl<DEV, RST, IRQ, E> BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi<Error = E>,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    /// Creates a new driver on an SPI device that handles chip select,
+    /// e.g. an embedded-hal 1.0 `SpiDevice` or a `SharedDevice`.
+    pub fn with_device(dev: DEV, rst: RST, irq: IRQ) -> Self {
+        BmLite {
+            dev,
+            rst,
+            irq,
+            power: PowerState::Active,
+            retry: RetryPolicy::default(),
+            link: LinkConfig::default(),
+            link_failures: 0,
+            rx: Bytes::new(),
+            rx_frame: 1,
+            rx_rerequests: 0,
+            pending: None,
+            idle: None,
+            delay: None,
+            #[cfg(feature = "trace")]
+            trace: None,
+            audit: None,
+        }
+    }
+
+    /// Return the SPI device and pins
+    pub fn release(self) -> (DEV, RST, IRQ) {
+        (self.dev, self.rst, self.irq)
     }
 
-    /// Reset sensor MCU subsystem. Provide a delay in closure      
-    pub fn reset<DelayClass>(&mut self, mut d: DelayClass) -> Result<u8, Error<E>>
+    /// Reset sensor MCU subsystem and wait for it to boot.
+    /// Ready is signalled by IRQ, if that does not happen within the boot
+    /// timeout the module is asked for its version instead.
+    pub fn reset<D>(&mut self, delay: &mut D) -> Result<BootState, Error<E>>
     where
-        DelayClass: FnMut(),
+        D: DelayNs + ?Sized,
     {
-        self.rst.set_low();
-        d();
-        self.rst.set_high();
-        Ok(0)
+        if self.pulse_reset(delay)? {
+            return Ok(BootState::IrqReady);
+        }
+        self.get_version()?;
+        Ok(BootState::VersionReady)
+    }
+
+    /// Hold the reset pin low, release it and wait for the module to raise
+    /// IRQ. Returns whether IRQ was seen within the boot timeout.
+    fn pulse_reset<D>(&mut self, delay: &mut D) -> Result<bool, Error<E>>
+    where
+        D: DelayNs + ?Sized,
+    {
+        self.rst.set_low().map_err(pin_error)?;
+        delay.delay_ms(RESET_HOLD_MS);
+        self.rst.set_high().map_err(pin_error)?;
+        self.power = PowerState::Active;
+        self.pending = None;
+
+        for _ in 0..BOOT_TIMEOUT_MS {
+            if self.irq.is_high().map_err(pin_error)? {
+                return Ok(true);
+            }
+            delay.delay_ms(1);
+            self.run_idle_hook()?;
+        }
+        Ok(false)
+    }
+
+    /// Delay the driver times reset pulses with when it resets the module
+    /// on its own, see `RetryPolicy::hardware_reset`
+    #[cfg(feature = "alloc")]
+    pub fn set_delay(&mut self, delay: Box<dyn DelayNs>) {
+        self.delay = Some(delay);
+    }
+
+    /// Delay the driver times reset pulses with when it resets the module
+    /// on its own, see `RetryPolicy::hardware_reset`
+    #[cfg(not(feature = "alloc"))]
+    pub fn set_delay(&mut self, delay: &'static mut dyn DelayNs) {
+        self.delay = Some(delay);
+    }
+
+    /// Set how the link recovers from CRC and framing errors. Hardware
+    /// reset needs a delay from `set_delay()` first.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> Result<(), Error<E>> {
+        if policy.hardware_reset && self.delay.is_none() {
+            return Err(Error::InvalidConfig);
+        }
+        self.retry = policy;
+        self.audit(AuditEvent::ConfigChanged(ConfigChange::RetryPolicy));
+        Ok(())
+    }
+
+    /// Set how frames are split in SPI transfers, for SPI drivers that
+    /// limit the transfer length or modules that need time between chunks.
+    /// Pauses need a device that can time them, e.g. an `ExclusiveDevice`
+    /// built `with_delay()`.
+    pub fn set_link_config(&mut self, config: LinkConfig) -> Result<(), Error<E>> {
+        let pauses = config.chunk_size != 0 && config.chunk_pause_ns != 0;
+        if pauses && !self.dev.can_pause() {
+            return Err(Error::InvalidConfig);
+        }
+        self.link = config;
+        self.audit(AuditEvent::ConfigChanged(ConfigChange::LinkConfig));
+        Ok(())
     }
 
-    fn link(&mut self, mut transport: Vec<u8>) -> Result<(Vec<u8>), Error<E>> {
-        let len = transport.len() as u32 - 10;
-        transport[2] = (len & 0xFF) as u8 + 6; // Size
-        transport[3] = 0x0; // MSB always 0
-        transport[4] = (len & 0xFF) as u8; // Size
-        transport[5] = 0x0; // MSB always 0
+    /// Call `hook` every time the driver waits for the sensor, e.g. to
+    /// kick a watchdog during `capture(0)`. A cancelled command may still
+    /// be running in the module, `reset()` stops it.
+    #[cfg(feature = "alloc")]
+    pub fn set_idle_hook(&mut self, hook: Box<dyn FnMut() -> IdleAction>) {
+        self.idle = Some(hook);
+    }
+
+    /// Call `hook` every time the driver waits for the sensor, e.g. to
+    /// kick a watchdog during `capture(0)`. A cancelled command may still
+    /// be running in the module, `reset()` stops it.
+    #[cfg(not(feature = "alloc"))]
+    pub fn set_idle_hook(&mut self, hook: &'static mut dyn FnMut() -> IdleAction) {
+        self.idle = Some(hook);
+    }
+
+    pub fn clear_idle_hook(&mut self) {
+        self.idle = None;
+    }
+
+    /// Power state last commanded, updated by sleep, wake and reset
+    pub fn power_state(&self) -> PowerState {
+        self.power
+    }
 
-        transport = transport.push_crc();
+    /// Put the module in low power mode with finger detect enabled.
+    /// A finger on the sensor wakes it, see `wake()`.
+    pub fn sleep(&mut self) -> Result<(), Error<E>> {
+        self.set_power_mode(ARG_SLEEP)?;
+        self.power = PowerState::Sleep;
+        Ok(())
+    }
 
-        self.cs.set_low();
-        let _ans = self.spi.transfer(&mut transport).map_err(Error::HalErr)?;
-        self.cs.set_high();
+    /// Put the module in deep sleep. Only `reset()` wakes it from here,
+    /// commands sent before that return `Error::Sleeping`.
+    pub fn deep_sleep(&mut self) -> Result<(), Error<E>> {
+        self.set_power_mode(ARG_DEEP_SLEEP)?;
+        self.power = PowerState::DeepSleep;
+        Ok(())
+    }
 
-        let mut timeout: i32 = 500_000;
-        while self.irq.is_low() {
-            timeout -= 1;
-            if timeout < 0 {
-                return Err(Error::Timeout);
+    /// Wake path driven by the IRQ pin. The module raises IRQ when a finger
+    /// wakes it from sleep; call this from the pin interrupt or poll it
+    /// with `block!`. Returns `WouldBlock` until IRQ is seen.
+    pub fn wake(&mut self) -> nb::Result<(), Error<E>> {
+        match self.power {
+            PowerState::Active => Ok(()),
+            PowerState::DeepSleep => Err(nb::Error::Other(Error::Sleeping)),
+            PowerState::Sleep => {
+                if self.irq.is_high().map_err(pin_error)? {
+                    self.power = PowerState::Active;
+                    Ok(())
+                } else {
+                    Err(nb::Error::WouldBlock)
+                }
             }
         }
-        self.cs.set_low();
-        let mut ack: Vec<u8> = [0, 0, 0, 0].to_vec();
-        let ack = self.spi.transfer(&mut ack).map_err(Error::HalErr)?;
-        self.cs.set_high();
+    }
 
-        // expect magic 7f ff 01 7f
-        if !(ack[0] == 0x7f && ack[1] == 0xff && ack[2] == 0x01 && ack[3] == 0x7f) {
-            return Err(Error::UnexpectedResponse);
+    fn set_power_mode(&mut self, mode: u16) -> Result<(), Error<E>> {
+        let cmd = CMD_MCU;
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
+            .set_cmd(cmd)
+            .add_arg(mode);
+        let resp = self.link(transport)?;
+
+        let mut ok_resp = false;
+        resp.parse_result(cmd, |arg, _argv, _arglen| {
+            match arg {
+                ARG_RESULT => ok_resp = true,
+                _other => {} // For args we do not care about
+            }
+        })?;
+        if ok_resp {
+            return Ok(());
+        }
+        Err(Error::UnexpectedResponse)
+    }
+
+    /// Send a command and return its response package, which borrows the
+    /// driver until the next command
+    fn link(&mut self, transport: Bytes) -> Result<&[u8], Error<E>> {
+        let cmd = self.link_send(&transport)?;
+        self.link_receive(cmd)
+    }
+
+    /// First half of `link()`, send the command and return the command
+    /// the response will be for
+    fn link_send(&mut self, transport: &Bytes) -> Result<u16, Error<E>> {
+        if self.pending.is_some() {
+            return Err(Error::Busy);
+        }
+        match self.power {
+            PowerState::DeepSleep => return Err(Error::Sleeping),
+            // Host traffic wakes the module from finger detect sleep
+            PowerState::Sleep => self.power = PowerState::Active,
+            PowerState::Active => {}
         }
-        //timeout = 500_000;
-        while self.irq.is_low() {
-            //   timeout -=1;
-            //   if timeout < 0 {
-            //       return Err(Error::Timeout)
-            //   }
+        if buffer::overflowed(transport) {
+            return Err(Error::BufferFull);
         }
-        self.cs.set_low();
-        let mut v0: Vec<u8> = [0, 0, 0, 0].to_vec();
-        let v0 = self.spi.transfer(&mut v0).map_err(Error::HalErr)?;
-        self.cs.set_high();
+        let cmd = transport.get_cmd().unwrap_or(0);
+        if let Err(e) = self.send_app(&transport[10..]) {
+            return Err(self.link_error(e));
+        }
+        // Response is read from its first frame into an empty `rx`
+        self.rx.clear();
+        self.rx_frame = 1;
+        self.rx_rerequests = 0;
+        Ok(cmd)
+    }
 
-        let transportsize: usize = 4 + v0[2] as usize;
-        let mut v: Vec<u8> = Vec::with_capacity(transportsize);
-        self.cs.set_low();
-        for _i in 0..transportsize {
-            v.push(0);
+    /// Second half of `link()`, read the response as IRQ is raised for
+    /// each of its frames
+    fn link_receive(&mut self, cmd: u16) -> Result<&[u8], Error<E>> {
+        loop {
+            if let Err(e) = self.wait_irq(None) {
+                return Err(self.link_error(e));
+            }
+            if self.link_read(cmd)? {
+                return Ok(&self.rx);
+            }
         }
-        {
-            // Scope off mutable borrow of v
-            // internal array of v is returned in _ans but updated data still pressent
-            // in vector v when the mutable borrow ends.
-            let _ans = self.spi.transfer(&mut v).map_err(Error::HalErr)?;
+    }
+
+    /// Read the next frame of the response to `cmd` with IRQ raised, true
+    /// once the response is complete
+    fn link_read(&mut self, cmd: u16) -> Result<bool, Error<E>> {
+        match self.read_next(cmd) {
+            Ok(done) => {
+                if done {
+                    self.link_failures = 0;
+                }
+                Ok(done)
+            }
+            Err(e) => Err(self.link_error(e)),
         }
-        self.cs.set_high();
+    }
 
-        let crc = crc32::checksum_ieee(&v[0..transportsize - 4]);
+    /// Count `e` as a failed command unless the idle hook cancelled it
+    fn link_error(&mut self, e: Error<E>) -> Error<E> {
+        match e {
+            // Not a link failure
+            Error::Cancelled => {}
+            _ => self.link_failed(),
+        }
+        e
+    }
 
-        if crc == LittleEndian::read_u32(&v[transportsize - 4..transportsize]) {
-            self.cs.set_low();
-            let mut ack = [0x7f, 0xff, 0x01, 0x7f];
-            let _ack = self.spi.transfer(&mut ack).map_err(Error::HalErr)?;
-            self.cs.set_high();
-        } else {
-            //crc error
-            return Err(Error::CRCError);
+    /// Count a failed command, resync once too many failed in a row.
+    /// The failed command's error is what the caller reports, a failed
+    /// resync is tried again on the next failure.
+    fn link_failed(&mut self) {
+        self.link_failures = self.link_failures.saturating_add(1);
+        if self.link_failures >= self.retry.resync_after && self.resync().is_ok() {
+            self.link_failures = 0;
         }
+    }
 
-        // verify sizes v[0] and v[1] -- ignored
+    /// Send application package split in as many transport frames as
+    /// needed
+    fn send_app(&mut self, app: &[u8]) -> Result<(), Error<E>> {
+        let frames = app.len().div_ceil(TX_CHUNK) as u16;
+        for (i, chunk) in app.chunks(TX_CHUNK).enumerate() {
+            self.send_command(chunk, i as u16 + 1, frames)?;
+        }
+        Ok(())
+    }
 
-        // v[2:3] seq num
-        // v[4:5] seq len -- for multi frame package this will be where we have reading of multi data
+    /// Send `chunk` in command frame `nr` of `len`, resend it while the
+    /// sensor NACKs it, answers with garbage or does not answer at all.
+    /// The frame is built in the device's frame buffer, or in `rx` which
+    /// holds no response while sending.
+    fn send_command(&mut self, chunk: &[u8], nr: u16, len: u16) -> Result<(), Error<E>> {
+        let size = chunk.len() + FRAME_OVERHEAD;
+        let at = self.frame_at(0, size);
+        let mut resend = 0;
+        loop {
+            // Transfer is done in place, build the frame again every time
+            self.rx.clear();
+            transport_frame(self.frame_mut(at, size), chunk, nr, len);
+            self.trace(TraceEvent::Command(self.frame(at, size)));
+            self.transfer_at(at, size)?;
+
+            let err = match self.wait_irq(Some(500_000)) {
+                Ok(()) => {
+                    let mut ack = [0, 0, 0, 0];
+                    self.transfer_frame(&mut ack)?;
+                    self.trace(TraceEvent::CommandAck(ack));
+                    match ack {
+                        ACK => return Ok(()),
+                        NACK => Error::Nack,
+                        garbage => Error::BadAck(garbage),
+                    }
+                }
+                Err(Error::Timeout) => Error::Timeout,
+                Err(e) => return Err(e),
+            };
+            if resend >= self.retry.resend {
+                return Err(err);
+            }
+            resend += 1;
+        }
+    }
+
+    /// Read the next response frame, in sequence after those before it.
+    /// True once the last is read and the application package they carry
+    /// is in `rx`.
+    fn read_next(&mut self, cmd: u16) -> Result<bool, Error<E>> {
+        let (nr, len) = match self.read_frame()? {
+            Some(seq) => seq,
+            // NACKed, read again on the next IRQ
+            None => return Ok(false),
+        };
+        if nr != self.rx_frame || nr > len {
+            return Err(Error::Frame(FrameError::Sequence { nr, len }));
+        }
+        if nr < len {
+            self.rx_frame += 1;
+            return Ok(false);
+        }
 
-        if (v[2], v[3]) != (v[4], v[5]) {
-            // multi packet not expected and supported yet
+        // app[0:1] CMD should be same as CMD sent.
+        // app[2:3] argument count
+        let app = &self.rx;
+        if app.len() < 4 {
             return Err(Error::UnexpectedResponse);
         }
+        let received = as_u16(app[1], app[0]);
+        if received != cmd {
+            return Err(Error::Frame(FrameError::Command {
+                sent: cmd,
+                received,
+            }));
+        }
+        Ok(true)
+    }
+
+    /// Read one transport frame with IRQ raised, NACK it on CRC error so
+    /// the sensor sends it again. The frame is read in the device's frame
+    /// buffer if it fits, else in `rx`. The validated frame's payload is
+    /// added to `rx`, returns its sequence number and length or None if
+    /// it was NACKed.
+    fn read_frame(&mut self) -> Result<Option<(u16, u16)>, Error<E>> {
+        let start = self.rx.len();
+        // v0[0:1] channel, v0[2:3] link size
+        let mut v0 = [0, 0, 0, 0];
+        self.transfer_frame(&mut v0)?;
+        self.trace(TraceEvent::Header(v0));
+
+        // Sensor answers on channel 0 or echoes the command channel
+        let channel = as_u16(v0[1], v0[0]);
+        if channel != 0 && channel != HCP_CHANNEL {
+            self.reject_frame()?;
+            return Err(Error::Frame(FrameError::Channel(channel)));
+        }
+        let linksize = as_u16(v0[3], v0[2]);
+        if !(MIN_LINK_SIZE..=MAX_LINK_SIZE).contains(&linksize) {
+            self.reject_frame()?;
+            return Err(Error::Frame(FrameError::LinkSize(linksize)));
+        }
+
+        // An ACKed frame cannot be refused, check for room before
+        let transportsize: usize = 4 + linksize as usize;
+        if !buffer::has_room(&self.rx, transportsize) {
+            self.reject_frame()?;
+            return Err(Error::BufferFull);
+        }
+        let at = self.frame_at(start, transportsize);
+        for byte in self.frame_mut(at, transportsize) {
+            *byte = 0;
+        }
+        self.transfer_at(at, transportsize)?;
+        let frame = self.frame(at, transportsize);
+        self.trace(TraceEvent::Response(frame));
+
+        let size = transportsize - 4;
+        if crc32::checksum_ieee(&frame[..size]) != LittleEndian::read_u32(&frame[size..]) {
+            //crc error, the frame read again replaces this one
+            self.rx.truncate(start);
+            if self.rx_rerequests >= self.retry.rerequest {
+                return Err(Error::CRCError);
+            }
+            self.rx_rerequests += 1;
+            self.trace(TraceEvent::ResponseAck(NACK));
+            let mut nack = NACK;
+            self.transfer_frame(&mut nack)?;
+            return Ok(None);
+        }
+        // The ACK may be moved through the frame buffer
+        let v = self.keep_payload(at, size);
+        self.rx_rerequests = 0;
+        self.trace(TraceEvent::ResponseAck(ACK));
+        let mut ack = ACK;
+        self.transfer_frame(&mut ack)?;
+
+        // v[0:1] transport size, everything after the transport header
+        // v[2:3] seq num
+        // v[4:5] seq len
+        let linksize = size as u16;
+        let transport = as_u16(v[1], v[0]);
+        if transport as u32 + 6 != linksize as u32 {
+            return Err(Error::Frame(FrameError::TransportSize {
+                link: linksize,
+                transport,
+            }));
+        }
+        Ok(Some((as_u16(v[3], v[2]), as_u16(v[5], v[4]))))
+    }
+
+    /// Add the payload of the `size` bytes frame at `at` to `rx`, without
+    /// its CRC. Returns the transport header.
+    fn keep_payload(&mut self, at: FrameAt, size: usize) -> [u8; 6] {
+        let mut header = [0; 6];
+        header.copy_from_slice(&self.frame(at, size)[..6]);
+        match at {
+            FrameAt::Device => {
+                if let Some(buf) = self.dev.frame_buffer() {
+                    self.rx.extend_from_slice(&buf[6..size]);
+                }
+            }
+            FrameAt::Rx(start) => {
+                self.rx.copy_within(start + 6..start + size, start);
+                self.rx.truncate(start + size - 6);
+            }
+        }
+        header
+    }
+
+    /// Where to build or read a frame of `len` bytes, in the device's
+    /// frame buffer if it fits there, else in `rx` from `start`
+    fn frame_at(&self, start: usize, len: usize) -> FrameAt {
+        match self.dev.frame_buffer() {
+            Some(buf) if buf.len() >= len => FrameAt::Device,
+            _ => FrameAt::Rx(start),
+        }
+    }
+
+    /// Frame of `len` bytes at `at` to build or read in, `rx` is grown
+    /// to hold it
+    fn frame_mut(&mut self, at: FrameAt, len: usize) -> &mut [u8] {
+        match at {
+            FrameAt::Device => match self.dev.frame_buffer_mut() {
+                Some(buf) => &mut buf[..len],
+                None => &mut [],
+            },
+            FrameAt::Rx(start) => {
+                self.rx.resize(start + len, 0);
+                &mut self.rx[start..start + len]
+            }
+        }
+    }
+
+    /// Frame of `len` bytes at `at`
+    fn frame(&self, at: FrameAt, len: usize) -> &[u8] {
+        match at {
+            FrameAt::Device => self.dev.frame_buffer().map_or(&[], |buf| &buf[..len]),
+            FrameAt::Rx(start) => &self.rx[start..start + len],
+        }
+    }
+
+    /// Transfer the frame of `len` bytes at `at` in place
+    fn transfer_at(&mut self, at: FrameAt, len: usize) -> Result<(), Error<E>> {
+        match at {
+            FrameAt::Device => self
+                .dev
+                .buffer_transaction(
+                    len,
+                    self.link.chunk_size,
+                    self.link.chunk_pause_ns,
+                    &mut idle_call(&mut self.idle),
+                )
+                .map_err(Error::HalErr),
+            FrameAt::Rx(start) => transfer(
+                &mut self.dev,
+                &self.link,
+                &mut self.idle,
+                &mut self.rx[start..start + len],
+            ),
+        }
+    }
+
+    /// Bring host and sensor back in step after repeated link failures.
+    /// Either pulse the reset pin and wait for boot, or clock out and
+    /// drop whatever the sensor still has pending.
+    fn resync(&mut self) -> Result<(), Error<E>> {
+        if self.retry.hardware_reset {
+            // Only a boxed delay needs to be mut
+            #[allow(unused_mut)]
+            let mut delay = self.delay.take().ok_or(Error::InvalidConfig)?;
+            let booted = self.pulse_reset(&mut *delay);
+            self.delay = Some(delay);
+            return match booted? {
+                true => Ok(()),
+                false => Err(Error::Timeout),
+            };
+        }
+        self.drain()
+    }
+
+    /// NACK a frame whose header cannot be trusted and drop what the
+    /// sensor sends after it, the frame length is unknown
+    fn reject_frame(&mut self) -> Result<(), Error<E>> {
+        self.trace(TraceEvent::ResponseAck(NACK));
+        let mut nack = NACK;
+        self.transfer_frame(&mut nack)?;
+        self.drain()
+    }
+
+    /// Clock out and drop whatever the sensor still has pending
+    fn drain(&mut self) -> Result<(), Error<E>> {
+        let mut pending = 0;
+        while self.irq.is_high().map_err(pin_error)? && pending < 64 {
+            let mut discard = [0, 0, 0, 0];
+            self.transfer_frame(&mut discard)?;
+            pending += 1;
+            self.run_idle_hook()?;
+        }
+        Ok(())
+    }
+
+    /// Call the idle hook, `Error::Cancelled` if it asks to stop waiting
+    fn run_idle_hook(&mut self) -> Result<(), Error<E>> {
+        if let Some(ref mut idle) = self.idle {
+            if idle() == IdleAction::Cancel {
+                return Err(Error::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the sensor to raise IRQ, timeout is in polls of the pin
+    fn wait_irq(&mut self, timeout: Option<i32>) -> Result<(), Error<E>> {
+        let mut timeout = timeout;
+        while self.irq.is_low().map_err(pin_error)? {
+            self.run_idle_hook()?;
+            if let Some(ref mut t) = timeout {
+                *t -= 1;
+                if *t < 0 {
+                    return Err(Error::Timeout);
+                }
+            }
+        }
+        Ok(())
+    }
 
-        // v[6:7] application package:  (maybe num commands)
-        // v[8:9] CMD should be same as CMD sent.
-        Ok(v.split_off(6))
+    /// One transfer framed by chip select
+    fn transfer_frame(&mut self, buf: &mut [u8]) -> Result<(), Error<E>> {
+        transfer(&mut self.dev, &self.link, &mut self.idle, buf)
     }
 
-    pub fn get_version(&mut self) -> Result<Vec<u8>, Error<E>> {
+    /// Firmware version string, borrowed from the response
+    pub fn get_version(&mut self) -> Result<&[u8], Error<E>> {
         let cmd = 0x3004;
 
-        let transport = <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer()
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
             .set_cmd(cmd)
             .add_arg(ARG_GET)
             .add_arg(ARG_VERSION);
@@ -344,13 +1196,11 @@ where
 
         // handle all responses here
         let mut ok_resp = false;
-        let mut version: Vec<u8> = Vec::with_capacity(64);
+        let mut version: &[u8] = &[];
         resp.parse_result(cmd, |arg, argv, _arglen| {
             match arg {
                 ARG_RESULT => ok_resp = true,
-                ARG_VERSION => {
-                    version.extend_from_slice(&argv);
-                }
+                ARG_VERSION => version = argv,
                 //   ARG_ID  => { remaining = (LittleEndian::read_uint(&argv,arglen) & 0xFFFF_FFFF )as u32; }
                 _other => {} // For args we do not care about
             }
@@ -362,78 +1212,104 @@ where
     }
     // Timeout in ms but 0 waits forever
     pub fn capture(&mut self, timeout: u32) -> Result<u8, Error<E>> {
-        let cmd = 0x0001;
-        let mut transport =
-            <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer().set_cmd(cmd);
-        if timeout != 0 {
-            transport = transport.add_arg_u32(0x5001, timeout);
-        }
-        let resp = self.link(transport)?;
-
-        let mut captureresult = 0;
-        let mut ok_resp = false;
-        resp.parse_result(cmd, |arg, argv, arglen| {
-            match arg {
-                ARG_RESULT => {
-                    ok_resp = true;
-                    captureresult = (LittleEndian::read_uint(&argv, arglen) & 0xFFFF_FFFF) as u32;
-                }
-                _other => {} // For args we do not care about
-            }
-        })?;
-        if ok_resp {
-            return Ok(captureresult as _);
-        }
-        Err(Error::UnexpectedResponse)
+        let resp = self.link(capture_command(timeout))?;
+        finger_result(resp, CMD_CAPTURE)
+    }
+    /// Enroll a new finger, progress is reported to `f` at every step.
+    pub fn enroll<F>(&mut self, f: F) -> Result<u32, Error<E>>
+    where
+        F: FnMut(EnrollEvent),
+    {
+        self.enroll_with(EnrollOptions::default(), f)
     }
-    pub fn enroll<F>(&mut self, mut f: F) -> Result<u32, Error<E>>
+
+    /// Enroll a new finger as `enroll()`, with `options` checked before
+    /// the template is saved.
+    pub fn enroll_with<F>(&mut self, options: EnrollOptions, f: F) -> Result<u32, Error<E>>
     where
-        F: FnMut(u32),
+        F: FnMut(EnrollEvent),
     {
-        let next_template_id = 1 + self.get_template_count()?;
-        let mut enrolling = 100;
-        f(enrolling as u32);
-        self.do_enroll(0x03)?; //begin
-        while enrolling > 0 {
-            f(enrolling as u32);
-            self.waitfingerup(0)?;
-            self.capture(0)?;
-            enrolling = self.do_enroll(0x04)?; //add image
-        }
-        self.do_enroll(0x05)?; //done
-        self.do_savetemplate(next_template_id as u16)?;
+        self.enroll_id(options, f)?;
         Ok(0)
     }
 
+    /// Enroll as `enroll_with()` and return the id the template was saved
+    /// under, the lowest one free in sensor storage
+    pub(crate) fn enroll_id<F>(&mut self, options: EnrollOptions, mut f: F) -> Result<u16, Error<E>>
+    where
+        F: FnMut(EnrollEvent),
+    {
+        let next_template_id = self.next_template_id()?;
+        let mut session = self.begin_enroll()?;
+        f(EnrollEvent::Started);
+        let complete = loop {
+            f(EnrollEvent::LiftFinger);
+            session.wait_finger_up(0)?;
+            f(EnrollEvent::PlaceFinger);
+            // Session dropped with the error aborts the enrollment
+            session = match session.add_image(0).map_err(|(_session, e)| e)? {
+                AddImage::More(session, remaining) => {
+                    f(EnrollEvent::ImageAccepted { remaining });
+                    session
+                }
+                AddImage::Rejected(session, reason) => {
+                    f(EnrollEvent::ImageRejected { reason });
+                    session
+                }
+                AddImage::Complete(complete) => {
+                    f(EnrollEvent::ImageAccepted { remaining: 0 });
+                    break complete;
+                }
+            };
+        };
+        f(EnrollEvent::Saving);
+        let id = complete.finish_with(next_template_id, options)?;
+        f(EnrollEvent::Done { id });
+        Ok(next_template_id)
+    }
+
+    #[deprecated(note = "states can be sent in any order, use begin_enroll()")]
     pub fn do_enroll(&mut self, state: u16) -> Result<u32, Error<E>> {
+        let (_result, remaining) = self.enroll_state(state)?;
+        Ok(remaining)
+    }
+
+    /// Run one enroll state, returns sensor result code and images remaining
+    fn enroll_state(&mut self, state: u16) -> Result<(u32, u32), Error<E>> {
         let cmd = 0x0002;
         let mut transport =
-            <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer().set_cmd(cmd);
+            <Bytes as TransportBuffer<Bytes>>::create_transport_buffer().set_cmd(cmd);
         if state != 0 {
             transport = transport.add_arg(state);
         }
         let resp = self.link(transport)?;
         // handle all responses here
+        let mut result: u32 = 0;
         let mut remaining: u32 = 0;
         let mut ok_resp = false;
         resp.parse_result(cmd, |arg, argv, arglen| {
             match arg {
-                ARG_RESULT => ok_resp = true,
+                ARG_RESULT => {
+                    ok_resp = true;
+                    if arglen > 0 {
+                        result = (LittleEndian::read_uint(argv, arglen) & 0xFFFF_FFFF) as u32;
+                    }
+                }
                 ARG_COUNT => {
-                    remaining = (LittleEndian::read_uint(&argv, arglen) & 0xFFFF_FFFF) as u32;
+                    remaining = (LittleEndian::read_uint(argv, arglen) & 0xFFFF_FFFF) as u32;
                 }
                 _other => {} // For args we do not care about
             }
         })?;
         if ok_resp {
-            return Ok(remaining);
+            return Ok((result, remaining));
         }
         Err(Error::UnexpectedResponse)
     }
 
     pub fn do_savetemplate(&mut self, tplid: u16) -> Result<u32, Error<E>> {
         let cmd = 0x0006;
-        let transport = <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer()
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
             .set_cmd(cmd)
             .add_arg(0x1008)
             .add_arg_u16(0x0006, tplid);
@@ -452,11 +1328,31 @@ where
         Err(Error::UnexpectedResponse)
     }
 
+    /// Drop the template held in sensor RAM, storage is not touched
+    fn discard_template(&mut self) -> Result<(), Error<E>> {
+        let cmd = 0x0006;
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
+            .set_cmd(cmd)
+            .add_arg(0x1009);
+        let resp = self.link(transport)?;
+        let mut ok_resp = false;
+        resp.parse_result(cmd, |arg, _argv, _arglen| {
+            match arg {
+                ARG_RESULT => ok_resp = true,
+                _other => {} // For args we do not care about
+            }
+        })?;
+        if ok_resp {
+            return Ok(());
+        }
+        Err(Error::UnexpectedResponse)
+    }
+
     pub fn get_template_count(&mut self) -> Result<u32, Error<E>> {
         const ARG_COUNT: u16 = 0x2002;
         const CMD_STORAGE_TEMPLATE: u16 = 0x4002;
         let cmd = CMD_STORAGE_TEMPLATE;
-        let transport = <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer()
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
             .set_cmd(cmd)
             .add_arg(ARG_COUNT);
         let resp = self.link(transport)?;
@@ -467,7 +1363,7 @@ where
             match arg {
                 ARG_RESULT => ok_resp = true,
                 ARG_COUNT => {
-                    template_count = (LittleEndian::read_uint(&argv, arglen) & 0xFFFF_FFFF) as u32;
+                    template_count = (LittleEndian::read_uint(argv, arglen) & 0xFFFF_FFFF) as u32;
                 }
                 _other => {} // For args we do not care about
             }
@@ -480,7 +1376,7 @@ where
 
     pub fn do_extract(&mut self) -> Result<u32, Error<E>> {
         let cmd = 0x0005;
-        let transport = <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer()
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
             .set_cmd(cmd)
             .add_arg(0x0008);
 
@@ -494,7 +1390,7 @@ where
             match arg {
                 ARG_RESULT => ok_resp = true,
                 ARG_COUNT => {
-                    remaining = (LittleEndian::read_uint(&argv, arglen) & 0xFFFF_FFFF) as u32;
+                    remaining = (LittleEndian::read_uint(argv, arglen) & 0xFFFF_FFFF) as u32;
                 }
                 _other => {} // For args we do not care about
             }
@@ -508,12 +1404,17 @@ where
     pub fn identify(&mut self) -> Result<u32, Error<E>> {
         self.capture(0)?;
         self.do_extract()?;
-        self.do_identify()
+        let result = self.do_identify();
+        match result {
+            Ok(id) => self.audit(AuditEvent::Identified { id }),
+            Err(Error::NoMatch) => self.audit(AuditEvent::IdentifyFailed),
+            Err(_) => {}
+        }
+        result
     }
     pub fn do_identify(&mut self) -> Result<u32, Error<E>> {
         let cmd = 0x0003;
-        let transport =
-            <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer().set_cmd(cmd);
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer().set_cmd(cmd);
 
         let resp = self.link(transport)?;
         // handle all responses here
@@ -524,10 +1425,10 @@ where
             match arg {
                 ARG_RESULT => ok_resp = true,
                 ARG_MATCH => {
-                    litematch = (LittleEndian::read_uint(&argv, arglen) & 0xFFFF_FFFF) as u32;
+                    litematch = (LittleEndian::read_uint(argv, arglen) & 0xFFFF_FFFF) as u32;
                 }
                 ARG_ID => {
-                    remaining = (LittleEndian::read_uint(&argv, arglen) & 0xFFFF_FFFF) as u32;
+                    remaining = (LittleEndian::read_uint(argv, arglen) & 0xFFFF_FFFF) as u32;
                 }
                 _other => {} // For args we do not care about
             }
@@ -542,34 +1443,153 @@ where
     }
 
     pub fn waitfingerup(&mut self, timeout: u32) -> Result<u8, Error<E>> {
-        let cmd = 0x007;
-        let mut transport =
-            <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer().set_cmd(cmd);
-        if timeout != 0 {
-            transport = transport.add_arg_u32(0x5001, timeout);
+        let resp = self.link(finger_up_command(timeout))?;
+        finger_result(resp, CMD_WAIT_FINGER_UP)
+    }
+    /// Lowest id free in sensor storage
+    fn next_template_id(&mut self) -> Result<u16, Error<E>> {
+        let cmd = 0x4002;
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
+            .set_cmd(cmd)
+            .add_arg(ARG_ID);
+        let resp = self.link(transport)?;
+
+        let mut ok_resp = false;
+        // Storage is empty when no id list is sent
+        let mut next = Some(1);
+        resp.parse_result(cmd, |arg, argv, _arglen| {
+            match arg {
+                ARG_RESULT => ok_resp = true,
+                ARG_DATA => {
+                    let ids = argv
+                        .chunks(2)
+                        .filter(|c| c.len() == 2)
+                        .map(LittleEndian::read_u16);
+                    next = lowest_free_id(ids);
+                }
+                _other => {} // For args we do not care about
+            }
+        })?;
+        if !ok_resp {
+            return Err(Error::UnexpectedResponse);
         }
-        transport = transport.add_arg(0x0002); //0002 Enroll
+        // Every id is in use
+        next.ok_or(Error::UnexpectedResponse)
+    }
 
+    /// Ids of all templates in sensor storage
+    #[cfg(feature = "alloc")]
+    pub fn template_ids(&mut self) -> Result<Vec<u16>, Error<E>> {
+        let cmd = 0x4002;
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
+            .set_cmd(cmd)
+            .add_arg(ARG_ID);
         let resp = self.link(transport)?;
-        let mut fingerpresent = 0;
+
         let mut ok_resp = false;
-        resp.parse_result(cmd, |arg, argv, arglen| {
+        let mut ids: Vec<u16> = Vec::new();
+        resp.parse_result(cmd, |arg, argv, _arglen| {
             match arg {
-                ARG_RESULT => {
-                    ok_resp = true;
-                    fingerpresent = (LittleEndian::read_uint(&argv, arglen) & 0xFFFF_FFFF) as u32;
+                ARG_RESULT => ok_resp = true,
+                ARG_DATA => {
+                    for id in argv.chunks(2).filter(|c| c.len() == 2) {
+                        ids.push(LittleEndian::read_u16(id));
+                    }
                 }
                 _other => {} // For args we do not care about
             }
         })?;
         if ok_resp {
-            return Ok(fingerpresent as _);
+            return Ok(ids);
+        }
+        Err(Error::UnexpectedResponse)
+    }
+
+    /// Load template `id` from storage into sensor RAM
+    #[cfg(feature = "alloc")]
+    pub fn load_template(&mut self, id: u16) -> Result<(), Error<E>> {
+        let cmd = 0x4002;
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
+            .set_cmd(cmd)
+            .add_arg(ARG_UPLOAD)
+            .add_arg_u16(ARG_ID, id);
+        self.template_command(cmd, transport).map(|_| ())
+    }
+
+    /// Read the template in sensor RAM to the host
+    #[cfg(feature = "alloc")]
+    pub fn read_template(&mut self) -> Result<Vec<u8>, Error<E>> {
+        let cmd = 0x0006;
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
+            .set_cmd(cmd)
+            .add_arg(ARG_UPLOAD);
+        match self.template_command(cmd, transport)? {
+            Some(template) => Ok(template),
+            None => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Write a template from the host to sensor RAM, save it to storage
+    /// with `do_savetemplate()`
+    #[cfg(feature = "alloc")]
+    pub fn write_template(&mut self, template: &[u8]) -> Result<(), Error<E>> {
+        let cmd = 0x0006;
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
+            .set_cmd(cmd)
+            .add_arg(ARG_DOWNLOAD)
+            .add_arg_data(ARG_DATA, template);
+        self.template_command(cmd, transport).map(|_| ())
+    }
+
+    // Send template command, returns template data if the sensor sent any
+    #[cfg(feature = "alloc")]
+    fn template_command(
+        &mut self,
+        cmd: u16,
+        transport: Bytes,
+    ) -> Result<Option<Vec<u8>>, Error<E>> {
+        let resp = self.link(transport)?;
+
+        let mut ok_resp = false;
+        let mut data: Option<Vec<u8>> = None;
+        resp.parse_result(cmd, |arg, argv, _arglen| {
+            match arg {
+                ARG_RESULT => ok_resp = true,
+                ARG_DATA => data = Some(argv.to_vec()),
+                _other => {} // For args we do not care about
+            }
+        })?;
+        if ok_resp {
+            return Ok(data);
+        }
+        Err(Error::UnexpectedResponse)
+    }
+
+    /// Delete template `id` from sensor storage
+    pub fn delete_template(&mut self, id: u16) -> Result<(), Error<E>> {
+        let cmd = 0x4002;
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
+            .set_cmd(cmd)
+            .add_arg(0x1009)
+            .add_arg_u16(ARG_ID, id);
+        let resp = self.link(transport)?;
+
+        let mut ok_resp = false;
+        resp.parse_result(cmd, |arg, _argv, _arglen| {
+            match arg {
+                ARG_RESULT => ok_resp = true,
+                _other => {} // For args we do not care about
+            }
+        })?;
+        if ok_resp {
+            self.audit(AuditEvent::Deleted { id });
+            return Ok(());
         }
         Err(Error::UnexpectedResponse)
     }
     pub fn delete_all(&mut self) -> Result<u8, Error<E>> {
         let cmd = 0x4002;
-        let transport = <Vec<u8> as TransportBuffer<Vec<u8>>>::create_transport_buffer()
+        let transport = <Bytes as TransportBuffer<Bytes>>::create_transport_buffer()
             .set_cmd(cmd)
             .add_arg(0x1009)
             .add_arg(0x0007);
@@ -581,12 +1601,15 @@ where
             match arg {
                 ARG_RESULT => {
                     ok_resp = true;
-                    deleteallresult = (LittleEndian::read_uint(&argv, arglen) & 0xFFFF_FFFF) as u32;
+                    deleteallresult = (LittleEndian::read_uint(argv, arglen) & 0xFFFF_FFFF) as u32;
                 }
                 _other => {} // For args we do not care about
             }
         })?;
         if ok_resp {
+            if deleteallresult == 0 {
+                self.audit(AuditEvent::DeletedAll);
+            }
             return Ok(deleteallresult as _);
         }
         Err(Error::UnexpectedResponse)
@@ -598,8 +1621,11 @@ mod tests {
 
     extern crate embedded_hal_mock;
     extern crate std;
+    use self::std::vec::Vec;
     use tests::embedded_hal_mock::gpio::*;
     use tests::embedded_hal_mock::spi::{Mock as SpiMock, Transaction as SpiTransaction};
+    use {BmLite, Bytes, SpiBmLite, TransportBuffer};
+
     #[test]
     fn capture_identify() {
         use super::*;
@@ -699,13 +1725,210 @@ mod tests {
         let mut bm = BmLite::new(spi, dummy_cs, dummy_reset, dummy_irq);
         let ans = bm.identify();
         match ans {
-            Err(_) => assert!(false, "Function returned unexpected error"),
+            Err(_) => panic!("Function returned unexpected error"),
             Ok(x) => assert!(x == 1),
         }
 
         let (mut spi, (_cs, _b, _c)) = bm.teardown();
         spi.done();
     }
+    // Expected SPI traffic for one command and the response frame it gets
+    pub fn link_transactions(command: &[u8], response: &[u8]) -> Vec<SpiTransaction> {
+        let size = response.len() - 4;
+        [
+            SpiTransaction::transfer(command.to_vec(), command.iter().map(|_| 0).collect()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+            SpiTransaction::transfer(
+                [0, 0, 0, 0].to_vec(),
+                [0, 0, size as u8, (size >> 8) as u8].to_vec(),
+            ),
+            SpiTransaction::transfer(response.iter().map(|_| 0).collect(), response.to_vec()),
+            SpiTransaction::transfer([0x7f, 0xff, 0x01, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+        ]
+        .to_vec()
+    }
+
+    // Package of command `cmd` as the driver starts it, arguments are added
+    // with the `TransportBuffer` methods
+    pub fn package(cmd: u16) -> Bytes {
+        <Bytes as TransportBuffer<Bytes>>::create_transport_buffer().set_cmd(cmd)
+    }
+
+    // Response to `cmd` carrying nothing but result code `result`
+    pub fn answer(cmd: u16, result: u8) -> Bytes {
+        package(cmd).add_arg_u8(::ARG_RESULT, result)
+    }
+
+    // Firmware version query answered with `version`
+    #[cfg(feature = "sealed")]
+    pub fn version_query(version: &[u8]) -> (Bytes, Bytes) {
+        use {ARG_GET, ARG_VERSION};
+        (
+            package(0x3004).add_arg(ARG_GET).add_arg(ARG_VERSION),
+            answer(0x3004, 0).add_arg_data(ARG_VERSION, version),
+        )
+    }
+
+    // Template id query answered with the ids `in_use`
+    pub fn template_ids(in_use: &[u16]) -> (Bytes, Bytes) {
+        use {ARG_DATA, ARG_ID};
+        let mut ids = answer(0x4002, 0);
+        if !in_use.is_empty() {
+            let data: Vec<u8> = in_use.iter().flat_map(|id| id.to_le_bytes()).collect();
+            ids = ids.add_arg_data(ARG_DATA, &data);
+        }
+        (package(0x4002).add_arg(ARG_ID), ids)
+    }
+
+    // Packages of an enrollment taking one image, from looking up the
+    // template ids `in_use` to finishing the template in sensor RAM
+    pub fn enroll_packages(in_use: &[u16]) -> Vec<(Bytes, Bytes)> {
+        use {ARG_COUNT, CMD_CAPTURE, CMD_WAIT_FINGER_UP};
+        [
+            template_ids(in_use),
+            // Enroll begin
+            (package(0x0002).add_arg(0x0003), answer(0x0002, 0)),
+            (::finger_up_command(0), answer(CMD_WAIT_FINGER_UP, 0)),
+            (::capture_command(0), answer(CMD_CAPTURE, 0)),
+            // Add image, none remaining
+            (
+                package(0x0002).add_arg(0x0004),
+                answer(0x0002, 0).add_arg_u32(ARG_COUNT, 0),
+            ),
+            // Enroll finish
+            (package(0x0002).add_arg(0x0005), answer(0x0002, 0)),
+        ]
+        .to_vec()
+    }
+
+    // `part` of a package in transport frame `nr` of `len`
+    pub fn frame(part: &[u8], nr: u16, len: u16) -> Vec<u8> {
+        let mut frame: Vec<u8> = (0..part.len() + ::FRAME_OVERHEAD).map(|_| 0).collect();
+        ::transport_frame(&mut frame, part, nr, len);
+        frame
+    }
+
+    // Expected SPI traffic and IRQ reads for `command` framed as
+    // `send_app()` frames it, answered by `response` in frames carrying
+    // `chunk` bytes of the package each
+    pub fn package_transactions(
+        command: &Bytes,
+        response: &Bytes,
+        chunk: usize,
+    ) -> (Vec<SpiTransaction>, Vec<bool>) {
+        let mut expectations: Vec<SpiTransaction> = Vec::new();
+        let mut irq: Vec<bool> = Vec::new();
+        let app = &command[10..];
+        let frames = app.len().div_ceil(::TX_CHUNK) as u16;
+        for (i, part) in app.chunks(::TX_CHUNK).enumerate() {
+            let frame = frame(part, i as u16 + 1, frames);
+            expectations.push(SpiTransaction::transfer(
+                frame.to_vec(),
+                frame.iter().map(|_| 0).collect(),
+            ));
+            irq.push(false);
+            expectations.push(SpiTransaction::transfer([0; 4].to_vec(), ::ACK.to_vec()));
+        }
+        let app = &response[10..];
+        let frames = app.len().div_ceil(chunk) as u16;
+        for (i, part) in app.chunks(chunk).enumerate() {
+            // Sensor answers on channel 0, the link size is left as framed
+            let frame = frame(part, i as u16 + 1, frames);
+            irq.push(false);
+            expectations.push(SpiTransaction::transfer(
+                [0; 4].to_vec(),
+                [0, 0, frame[2], frame[3]].to_vec(),
+            ));
+            expectations.push(SpiTransaction::transfer(
+                frame[4..].iter().map(|_| 0).collect(),
+                frame[4..].to_vec(),
+            ));
+            expectations.push(SpiTransaction::transfer(::ACK.to_vec(), [0; 4].to_vec()));
+        }
+        (expectations, irq)
+    }
+
+    // Expected SPI traffic and IRQ reads for commands each answered by a
+    // package in one frame
+    pub fn mock_packages(packages: &[(Bytes, Bytes)]) -> (Vec<SpiTransaction>, Vec<bool>) {
+        let mut expectations: Vec<SpiTransaction> = Vec::new();
+        let mut irq: Vec<bool> = Vec::new();
+        for (command, response) in packages.iter() {
+            let (e, i) = package_transactions(command, response, response.len());
+            expectations.extend(e);
+            irq.extend(i);
+        }
+        (expectations, irq)
+    }
+
+    pub type MockBmLite = SpiBmLite<SpiMock, DigitalIOMock, DigitalIOMock, DigitalIOMock>;
+
+    // Driver on mocks expecting `expectations` in order with chip select
+    // toggled around each, IRQ and reset read `irq` and `rst`
+    pub fn mock_driver(expectations: &[SpiTransaction], irq: &[bool], rst: &[bool]) -> MockBmLite {
+        let mut cs: Vec<bool> = Vec::with_capacity(2 * expectations.len());
+        for _ in expectations.iter() {
+            cs.push(false);
+            cs.push(true);
+        }
+        BmLite::new(
+            SpiMock::new(expectations),
+            DigitalIOMock::new("spi-cs", cs),
+            DigitalIOMock::new("spi-rst", rst.to_vec()),
+            DigitalIOMock::new("spi-irq", irq.to_vec()),
+        )
+    }
+
+    // Checks all expected SPI traffic took place
+    pub fn mock_done(bm: MockBmLite) {
+        let (mut spi, _pins) = bm.teardown();
+        spi.done();
+    }
+
+    // Counts ms, the driver never waits less than that
+    struct DelayCount(u32);
+    impl ::embedded_hal::delay::DelayNs for DelayCount {
+        fn delay_ns(&mut self, ns: u32) {
+            self.0 += ns / 1_000_000;
+        }
+        fn delay_ms(&mut self, ms: u32) {
+            self.0 += ms;
+        }
+    }
+
+    struct BrokenPin;
+    impl ::embedded_hal::digital::ErrorType for BrokenPin {
+        type Error = ::embedded_hal::digital::ErrorKind;
+    }
+    impl ::embedded_hal::digital::OutputPin for BrokenPin {
+        fn set_low(&mut self) -> Result<(), ::embedded_hal::digital::ErrorKind> {
+            Err(::embedded_hal::digital::ErrorKind::Other)
+        }
+        fn set_high(&mut self) -> Result<(), ::embedded_hal::digital::ErrorKind> {
+            Err(::embedded_hal::digital::ErrorKind::Other)
+        }
+    }
+
+    #[test]
+    fn reset_pin_error() {
+        use super::*;
+        let spi = SpiMock::new(&[]);
+        let dummy_cs = DigitalIOMock::new("spi-cs", [].to_vec());
+        let dummy_irq = DigitalIOMock::new("spi-irq", [].to_vec());
+
+        let dev = ExclusiveDevice::new(spi, dummy_cs);
+        let mut bm = BmLite::with_device(dev, BrokenPin, Compat::new(dummy_irq));
+        let mut delay = DelayCount(0);
+        match bm.reset(&mut delay) {
+            Err(Error::Pin(digital::ErrorKind::Other)) => {}
+            _ => panic!("Pin error was not reported"),
+        }
+        assert_eq!(delay.0, 0);
+        let (dev, _rst, _irq) = bm.release();
+        let (mut spi, _cs) = dev.release();
+        spi.done();
+    }
+
     #[test]
     fn reset_system() {
         use super::*;
@@ -714,24 +1937,73 @@ mod tests {
         let spi = SpiMock::new(&expectations);
 
         let dummy_cs = DigitalIOMock::new("spi-cs", [].to_vec());
-        let dummy_irq = DigitalIOMock::new("spi-irq", [].to_vec());
+        let dummy_irq = DigitalIOMock::new("spi-irq", [false].to_vec());
         let dummy_reset = DigitalIOMock::new("spi-rst", [false, true].to_vec());
 
         let mut bm = BmLite::new(spi, dummy_cs, dummy_reset, dummy_irq);
-        let mut callback_cnt = 0;
-        let ans = bm.reset(|| callback_cnt = callback_cnt + 1);
+        let mut delay = DelayCount(0);
+        let ans = bm.reset(&mut delay);
         match ans {
-            Err(_) => assert!(false, "Function returned unexpected error"),
-            Ok(_) => {}
+            Err(_) => panic!("Function returned unexpected error"),
+            Ok(state) => assert_eq!(state, BootState::IrqReady),
         }
-        assert!(callback_cnt == 1);
+        assert_eq!(delay.0, RESET_HOLD_MS);
         let (mut spi, (_cs, _b, _c)) = bm.teardown();
 
         spi.done();
     }
     #[test]
-    #[should_panic]
+    fn reset_system_version_handshake() {
+        use super::*;
+        let expectations = [
+            SpiTransaction::transfer(
+                [
+                    0x01, 0x00, 0x12, 0x00, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x04, 0x30, 0x02,
+                    0x00, 0x04, 0x10, 0x00, 0x00, 0x03, 0x60, 0x00, 0x00, 0x04, 0x90, 0xea, 0xde,
+                ]
+                .to_vec(),
+                [0; 26].to_vec(),
+            ),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0, 0, 0x17, 0].to_vec()),
+            SpiTransaction::transfer(
+                [0; 27].to_vec(),
+                [
+                    0x11, 0x00, 0x01, 0x00, 0x01, 0x00, 0x04, 0x30, 0x02, 0x00, 0x01, 0x20, 0x01,
+                    0x00, 0x00, 0x03, 0x60, 0x04, 0x00, 0x31, 0x2e, 0x32, 0x00, 0x55, 0xec, 0xba,
+                    0x87,
+                ]
+                .to_vec(),
+            ),
+            SpiTransaction::transfer([0x7f, 0xff, 0x01, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+        ];
+
+        let spi = SpiMock::new(&expectations);
+        let mut cs: Vec<bool> = Vec::with_capacity(expectations.len());
+        for _ in expectations.iter() {
+            cs.push(false);
+            cs.push(true);
+        }
+        // IRQ never rises during boot, then three reads for the version link
+        let mut irq = [true; BOOT_TIMEOUT_MS as usize].to_vec();
+        irq.extend_from_slice(&[false, true, false]);
+        let dummy_cs = DigitalIOMock::new("spi-cs", cs);
+        let dummy_irq = DigitalIOMock::new("spi-irq", irq);
+        let dummy_reset = DigitalIOMock::new("spi-rst", [false, true].to_vec());
 
+        let mut bm = BmLite::new(spi, dummy_cs, dummy_reset, dummy_irq);
+        let mut delay = DelayCount(0);
+        match bm.reset(&mut delay) {
+            Err(_) => panic!("Function returned unexpected error"),
+            Ok(state) => assert_eq!(state, BootState::VersionReady),
+        }
+        assert_eq!(delay.0, RESET_HOLD_MS + BOOT_TIMEOUT_MS);
+        let (mut spi, (_cs, _b, _c)) = bm.teardown();
+
+        spi.done();
+    }
+    #[test]
+    #[should_panic]
     fn reset_system_unsync() {
         use super::*;
         let expectations = [];
@@ -739,17 +2011,14 @@ mod tests {
         let spi = SpiMock::new(&expectations);
 
         let dummy_cs = DigitalIOMock::new("spi-cs", [].to_vec());
-        let dummy_irq = DigitalIOMock::new("spi-irq", [].to_vec());
+        let dummy_irq = DigitalIOMock::new("spi-irq", [false].to_vec());
         let dummy_reset = DigitalIOMock::new("spi-rst", [true, false, true].to_vec());
 
         let mut bm = BmLite::new(spi, dummy_cs, dummy_reset, dummy_irq);
-        let mut callback_cnt = 0;
-        let ans = bm.reset(|| callback_cnt = callback_cnt + 1);
-        match ans {
-            Err(_) => assert!(false, "Function returned unexpected error"),
-            Ok(_) => {}
-        }
-        assert!(callback_cnt == 1);
+        let mut delay = DelayCount(0);
+        let ans = bm.reset(&mut delay);
+        assert!(ans.is_ok(), "Function returned unexpected error");
+        assert_eq!(delay.0, RESET_HOLD_MS);
         let (mut spi, (_cs, _b, _c)) = bm.teardown();
 
         spi.done();
@@ -782,10 +2051,7 @@ mod tests {
 
         let mut bm = BmLite::new(spi, dummy_cs, dummy_reset, dummy_irq);
         let ans = bm.identify();
-        match ans {
-            Err(_x) => assert!(false, "Function returned unexpected error"),
-            Ok(_) => {}
-        }
+        assert!(ans.is_ok(), "Function returned unexpected error");
 
         let (mut spi, (_cs, _b, _c)) = bm.teardown();
 
@@ -841,10 +2107,7 @@ mod tests {
         let dummy_reset = DigitalIOMock::new("spi-rst", [false].to_vec());
         let mut bm = BmLite::new(spi, dummy_cs, dummy_reset, dummy_irq);
         let ans = bm.delete_all();
-        match ans {
-            Err(_x) => assert!(false, "Function returned unexpected error"),
-            Ok(_) => {}
-        }
+        assert!(ans.is_ok(), "Function returned unexpected error");
 
         let (mut spi, (_a, _b, _c)) = bm.teardown();
         spi.done();
@@ -900,7 +2163,7 @@ mod tests {
         let mut bm = BmLite::new(spi, dummy_cs, dummy_reset, dummy_irq);
         let ans = bm.capture(0);
         match ans {
-            Err(_x) => assert!(false, "Function returned unexpected error"),
+            Err(_x) => panic!("Function returned unexpected error"),
             Ok(ans) => assert!(ans == 0),
         }
 
@@ -915,7 +2178,7 @@ mod tests {
             SpiTransaction::transfer(
                 [
                     0x01, 0x00, 0x0E, 0x00, 0x08, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x40, 0x01,
-                    0x00, 0x02, 0x20, 0x00, 0x00, 0x6A, 0x6F, 0xB3, 0xC9,
+                    0x00, 0x06, 0x00, 0x00, 0x00, 0xDD, 0xBE, 0x9C, 0x7E,
                 ]
                 .to_vec(),
                 [
@@ -930,19 +2193,16 @@ mod tests {
             ),
             SpiTransaction::transfer(
                 [0x00, 0x00, 0x00, 0x00].to_vec(),
-                [0x00, 0x00, 0x17, 0x00].to_vec(),
+                [0x00, 0x00, 0x25, 0x00].to_vec(),
             ),
+            // Ids 1 to 8 and 10 are in use
             SpiTransaction::transfer(
+                [0; 41].to_vec(),
                 [
-                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                    0x00,
-                ]
-                .to_vec(),
-                [
-                    0x11, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x40, 0x02, 0x00, 0x02, 0x20, 0x04,
-                    0x00, 0x08, 0x00, 0x00, 0x00, 0x01, 0x20, 0x01, 0x00, 0x00, 0x26, 0xCF, 0xA1,
-                    0x1D,
+                    0x1F, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x40, 0x02, 0x00, 0x01, 0x20, 0x01,
+                    0x00, 0x00, 0x0A, 0x10, 0x12, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04,
+                    0x00, 0x05, 0x00, 0x06, 0x00, 0x07, 0x00, 0x08, 0x00, 0x0A, 0x00, 0xE3, 0x51,
+                    0xB4, 0x33,
                 ]
                 .to_vec(),
             ),
@@ -1410,19 +2670,749 @@ mod tests {
         let dummy_reset = DigitalIOMock::new("spi-rst", [false].to_vec());
 
         let mut bm = BmLite::new(spi, dummy_cs, dummy_reset, dummy_irq);
-        let mut callback_cnt = 0;
-        let ans = bm.enroll(|_| {
-            callback_cnt += 1;
-        });
+        let mut events: Vec<EnrollEvent> = Vec::new();
+        let ans = bm.enroll(|event| events.push(event));
         match ans {
-            Err(_x) => assert!(false, "Function returned unexpected error"),
+            Err(_x) => panic!("Function returned unexpected error"),
             Ok(ans) => assert!(ans == 0),
         }
-        // Expected number of calls to progress update callback
-        assert_eq!(callback_cnt, 4);
+        // Expected calls to progress update callback
+        assert_eq!(
+            events,
+            [
+                EnrollEvent::Started,
+                EnrollEvent::LiftFinger,
+                EnrollEvent::PlaceFinger,
+                EnrollEvent::ImageAccepted { remaining: 2 },
+                EnrollEvent::LiftFinger,
+                EnrollEvent::PlaceFinger,
+                EnrollEvent::ImageAccepted { remaining: 1 },
+                EnrollEvent::LiftFinger,
+                EnrollEvent::PlaceFinger,
+                EnrollEvent::ImageAccepted { remaining: 0 },
+                EnrollEvent::Saving,
+                EnrollEvent::Done { id: 9 },
+            ]
+            .to_vec()
+        );
 
         let (mut spi, (_a, _b, _c)) = bm.teardown();
         spi.done();
     }
 
+    #[test]
+    fn sleep_and_wake() {
+        use super::*;
+        let expectations = [
+            SpiTransaction::transfer(
+                [
+                    0x01, 0x00, 0x0e, 0x00, 0x08, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x50, 0x01,
+                    0x00, 0x02, 0x40, 0x00, 0x00, 0x84, 0x38, 0xba, 0xe6,
+                ]
+                .to_vec(),
+                [0; 22].to_vec(),
+            ),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0, 0, 0x0f, 0].to_vec()),
+            SpiTransaction::transfer(
+                [0; 19].to_vec(),
+                [
+                    0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x50, 0x01, 0x00, 0x01, 0x20, 0x01,
+                    0x00, 0x00, 0x80, 0x2e, 0x8e, 0xfc,
+                ]
+                .to_vec(),
+            ),
+            SpiTransaction::transfer([0x7f, 0xff, 0x01, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+        ];
+
+        // Three reads during the link, then IRQ stays low once before
+        // the finger wakes the module
+        let mut bm = mock_driver(&expectations, &[false, true, false, true, false], &[]);
+        assert!(bm.sleep().is_ok(), "Function returned unexpected error");
+        assert_eq!(bm.power_state(), PowerState::Sleep);
+        match bm.wake() {
+            Err(nb::Error::WouldBlock) => {}
+            _ => panic!("Woke without IRQ"),
+        }
+        match bm.wake() {
+            Ok(()) => {}
+            _ => panic!("Did not wake on IRQ"),
+        }
+        assert_eq!(bm.power_state(), PowerState::Active);
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn link_retry_ack_and_crc() {
+        let command: Vec<u8> = [
+            0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x52, 0x7c, 0x2b, 0x55,
+        ]
+        .to_vec();
+        let response: Vec<u8> = [
+            0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01, 0x00,
+            0x00, 0x83, 0xe1, 0x25, 0x90,
+        ]
+        .to_vec();
+        let mut corrupted = response.clone();
+        corrupted[12] ^= 0x40;
+
+        let expectations = [
+            SpiTransaction::transfer(command.clone(), [0; 18].to_vec()),
+            // Sensor did not ACK, command is sent again
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0, 0, 0, 0].to_vec()),
+            SpiTransaction::transfer(command, [0; 18].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0, 0, 17 - 2, 0].to_vec()),
+            // CRC fails, response is NACKed and read again
+            SpiTransaction::transfer([0; 19].to_vec(), corrupted),
+            SpiTransaction::transfer([0x7f, 0xff, 0x00, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0, 0, 17 - 2, 0].to_vec()),
+            SpiTransaction::transfer([0; 19].to_vec(), response),
+            SpiTransaction::transfer([0x7f, 0xff, 0x01, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+        ];
+
+        let mut bm = mock_driver(&expectations, &[false, false, false, false], &[]);
+        let ans = bm.capture(0);
+        match ans {
+            Err(_x) => panic!("Function returned unexpected error"),
+            Ok(ans) => assert!(ans == 0),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn link_nack_then_garbage() {
+        use super::*;
+        let command: Vec<u8> = [
+            0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x52, 0x7c, 0x2b, 0x55,
+        ]
+        .to_vec();
+
+        let expectations = [
+            SpiTransaction::transfer(command.clone(), [0; 18].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x00, 0x7f].to_vec()),
+            // NACK is retransmitted
+            SpiTransaction::transfer(command, [0; 18].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0x13, 0x01, 0x7f].to_vec()),
+        ];
+
+        let mut bm = mock_driver(&expectations, &[false, false], &[]);
+        assert!(bm
+            .set_retry_policy(RetryPolicy {
+                resend: 1,
+                ..RetryPolicy::default()
+            })
+            .is_ok());
+        match bm.capture(0) {
+            Err(Error::BadAck(word)) => assert_eq!(word, [0x7f, 0x13, 0x01, 0x7f]),
+            _ => panic!("Garbage ACK not reported"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn resync_pulses_reset_with_delay() {
+        use super::*;
+        use alloc::boxed::Box;
+        use alloc::rc::Rc;
+        use core::cell::Cell;
+
+        // Counts ms where the test can still read them
+        struct SharedDelay(Rc<Cell<u32>>);
+        impl DelayNs for SharedDelay {
+            fn delay_ns(&mut self, ns: u32) {
+                self.0.set(self.0.get() + ns / 1_000_000);
+            }
+        }
+
+        let expectations = [
+            SpiTransaction::transfer(
+                [
+                    0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+                    0x00, 0x52, 0x7c, 0x2b, 0x55,
+                ]
+                .to_vec(),
+                [0; 18].to_vec(),
+            ),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x00, 0x7f].to_vec()),
+        ];
+        // IRQ for the ACK, then raised as soon as the module is released
+        let mut bm = mock_driver(&expectations, &[false, false], &[false, true]);
+        let policy = RetryPolicy {
+            resend: 0,
+            resync_after: 1,
+            hardware_reset: true,
+            ..RetryPolicy::default()
+        };
+        match bm.set_retry_policy(policy) {
+            Err(Error::InvalidConfig) => {}
+            _ => panic!("Hardware reset accepted without a delay"),
+        }
+        let ms = Rc::new(Cell::new(0));
+        bm.set_delay(Box::new(SharedDelay(ms.clone())));
+        assert!(bm.set_retry_policy(policy).is_ok());
+
+        match bm.capture(0) {
+            Err(Error::Nack) => {}
+            _ => panic!("Resync replaced the link error"),
+        }
+        assert_eq!(ms.get(), RESET_HOLD_MS);
+        assert_eq!(bm.link_failures, 0);
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn link_config_pauses_need_delay() {
+        use super::*;
+        let mut bm = mock_driver(&[], &[], &[]);
+        // Transfer limits of any size are kept
+        let small = LinkConfig {
+            chunk_size: 4,
+            chunk_pause_ns: 0,
+        };
+        assert!(bm.set_link_config(small).is_ok());
+        let paced = LinkConfig {
+            chunk_pause_ns: 1000,
+            ..small
+        };
+        match bm.set_link_config(paced) {
+            Err(Error::InvalidConfig) => {}
+            _ => panic!("Pause accepted without a delay"),
+        }
+        assert_eq!(bm.link, small);
+
+        let (spi, (cs, rst, irq)) = bm.teardown();
+        let dev = ExclusiveDevice::with_delay(spi, cs, DelayCount(0));
+        let mut bm = BmLite::with_device(dev, Compat::new(rst), Compat::new(irq));
+        assert!(bm.set_link_config(paced).is_ok());
+    }
+
+    #[test]
+    fn response_for_other_command() {
+        use super::*;
+        let expectations = [
+            SpiTransaction::transfer(
+                [
+                    0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+                    0x00, 0x52, 0x7c, 0x2b, 0x55,
+                ]
+                .to_vec(),
+                [0; 18].to_vec(),
+            ),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0, 0, 17 - 2, 0].to_vec()),
+            // Well formed identify response to a capture command
+            SpiTransaction::transfer(
+                [0; 19].to_vec(),
+                [
+                    0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x03, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01,
+                    0x00, 0x00, 0x05, 0xc9, 0xd3, 0xbe,
+                ]
+                .to_vec(),
+            ),
+            SpiTransaction::transfer([0x7f, 0xff, 0x01, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+        ];
+
+        let mut bm = mock_driver(&expectations, &[false, false], &[]);
+        match bm.capture(0) {
+            Err(Error::Frame(FrameError::Command { sent, received })) => {
+                assert_eq!((sent, received), (0x0001, 0x0003))
+            }
+            _ => panic!("Command mismatch not reported"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn audit_records_delete_all() {
+        use super::*;
+        use alloc::boxed::Box;
+        use alloc::rc::Rc;
+        use core::cell::RefCell;
+
+        let expectations = link_transactions(
+            &[
+                0x01, 0x00, 0x12, 0x00, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x40, 0x02, 0x00,
+                0x09, 0x10, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0xb1, 0x2e, 0x45, 0x93,
+            ],
+            &[
+                0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x40, 0x01, 0x00, 0x01, 0x20, 0x01, 0x00,
+                0x00, 0xab, 0x1f, 0x35, 0x80,
+            ],
+        );
+        fn clock() -> u64 {
+            1234
+        }
+        let log = Rc::new(RefCell::new(RingLog::<8>::new()));
+        let mut bm = mock_driver(&expectations, &[false, false], &[]);
+        bm.set_audit(Box::new(log.clone()), clock);
+        assert_eq!(bm.delete_all().ok(), Some(0));
+
+        let records: Vec<AuditRecord> = log.borrow().records().cloned().collect();
+        assert_eq!(
+            records,
+            [AuditRecord {
+                time: 1234,
+                event: AuditEvent::DeletedAll,
+            }]
+            .to_vec()
+        );
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn link_rejects_bad_header() {
+        use super::*;
+        let headers = [[0x05, 0x00, 0x0f, 0x00], [0x00, 0x00, 0x00, 0x10]];
+        let mut expectations: Vec<SpiTransaction> = Vec::new();
+        let mut irq: Vec<bool> = Vec::new();
+        for header in headers.iter() {
+            expectations.push(SpiTransaction::transfer(
+                [
+                    0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+                    0x00, 0x52, 0x7c, 0x2b, 0x55,
+                ]
+                .to_vec(),
+                [0; 18].to_vec(),
+            ));
+            expectations.push(SpiTransaction::transfer(
+                [0, 0, 0, 0].to_vec(),
+                [0x7f, 0xff, 0x01, 0x7f].to_vec(),
+            ));
+            expectations.push(SpiTransaction::transfer(
+                [0, 0, 0, 0].to_vec(),
+                header.to_vec(),
+            ));
+            // NACK, then the rest of the frame is dropped
+            expectations.push(SpiTransaction::transfer(
+                [0x7f, 0xff, 0x00, 0x7f].to_vec(),
+                [0, 0, 0, 0].to_vec(),
+            ));
+            expectations.push(SpiTransaction::transfer(
+                [0, 0, 0, 0].to_vec(),
+                [0x09, 0x00, 0x01, 0x00].to_vec(),
+            ));
+            irq.extend_from_slice(&[false, false, false, true]);
+        }
+
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        match bm.capture(0) {
+            Err(Error::Frame(FrameError::Channel(5))) => {}
+            _ => panic!("Foreign channel not reported"),
+        }
+        match bm.capture(0) {
+            Err(Error::Frame(FrameError::LinkSize(0x1000))) => {}
+            _ => panic!("Oversized frame not reported"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    #[cfg(not(feature = "alloc"))]
+    fn response_larger_than_buffer_is_nacked() {
+        use super::*;
+        let capture = [
+            0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x52, 0x7c, 0x2b, 0x55,
+        ];
+        let first = frame(&[0; 1018], 1, 2);
+        let mut expectations = link_transactions(&capture, &first[4..]);
+        // Second full frame does not fit, refused before it is read
+        expectations.push(SpiTransaction::transfer(
+            [0, 0, 0, 0].to_vec(),
+            [0x00, 0x00, 0x00, 0x04].to_vec(),
+        ));
+        expectations.push(SpiTransaction::transfer(
+            [0x7f, 0xff, 0x00, 0x7f].to_vec(),
+            [0, 0, 0, 0].to_vec(),
+        ));
+
+        let mut bm = mock_driver(&expectations, &[false, false, false, true], &[]);
+        match bm.capture(0) {
+            Err(Error::BufferFull) => {}
+            _ => panic!("Oversized response not refused"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn link_crc_error_after_rerequests() {
+        use super::*;
+        let header = [0x00, 0x00, 0x0f, 0x00];
+        let corrupt = [
+            0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01, 0x00,
+            0x00, 0x83, 0xe1, 0x25, 0x6f,
+        ];
+        let mut expectations = [
+            SpiTransaction::transfer(
+                [
+                    0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+                    0x00, 0x52, 0x7c, 0x2b, 0x55,
+                ]
+                .to_vec(),
+                [0; 18].to_vec(),
+            ),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+        ]
+        .to_vec();
+        for i in 0..3 {
+            if i > 0 {
+                expectations.push(SpiTransaction::transfer(
+                    [0x7f, 0xff, 0x00, 0x7f].to_vec(),
+                    [0, 0, 0, 0].to_vec(),
+                ));
+            }
+            expectations.push(SpiTransaction::transfer(
+                [0, 0, 0, 0].to_vec(),
+                header.to_vec(),
+            ));
+            expectations.push(SpiTransaction::transfer([0; 19].to_vec(), corrupt.to_vec()));
+        }
+
+        // Default policy reads the frame again twice
+        let mut bm = mock_driver(&expectations, &[false, false, false, false], &[]);
+        match bm.capture(0) {
+            Err(Error::CRCError) => {}
+            _ => panic!("CRC error not reported"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn response_argument_overrun() {
+        use super::*;
+        // Capture response claiming two arguments but carrying one
+        let expectations = link_transactions(
+            &[
+                0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+                0x52, 0x7c, 0x2b, 0x55,
+            ],
+            &[
+                0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x00, 0x01, 0x20, 0x01, 0x00,
+                0x00, 0x1e, 0xfb, 0xcd, 0xa1,
+            ],
+        );
+
+        let mut bm = mock_driver(&expectations, &[false, false], &[]);
+        match bm.capture(0) {
+            Err(Error::Frame(FrameError::Argument(1))) => {}
+            _ => panic!("Argument overrun not reported"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn read_template_multi_frame() {
+        use super::*;
+        let response = package(0x0006)
+            .add_arg_data(ARG_DATA, &[1, 2, 3, 4, 5, 6, 7, 8])
+            .add_arg_u32(ARG_RESULT, 0);
+        // Two frames of 12 bytes, the template split between them
+        let (expectations, irq) =
+            package_transactions(&package(0x0006).add_arg(ARG_UPLOAD), &response, 12);
+
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        let template = bm.read_template();
+        assert!(template.is_ok());
+        assert_eq!(template.ok().unwrap(), [1, 2, 3, 4, 5, 6, 7, 8].to_vec());
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn enroll_session_aborts_on_drop() {
+        let expectations = [
+            SpiTransaction::transfer(
+                [
+                    0x01, 0x00, 0x0e, 0x00, 0x08, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x00, 0x01,
+                    0x00, 0x03, 0x00, 0x00, 0x00, 0x96, 0x3b, 0x49, 0x0d,
+                ]
+                .to_vec(),
+                [0; 22].to_vec(),
+            ),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0, 0, 0x0f, 0].to_vec()),
+            SpiTransaction::transfer(
+                [0; 19].to_vec(),
+                [
+                    0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01,
+                    0x00, 0x00, 0x46, 0xdd, 0xa8, 0xa9,
+                ]
+                .to_vec(),
+            ),
+            SpiTransaction::transfer([0x7f, 0xff, 0x01, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+            // Template in RAM is deleted when the session is dropped
+            SpiTransaction::transfer(
+                [
+                    0x01, 0x00, 0x0e, 0x00, 0x08, 0x00, 0x01, 0x00, 0x01, 0x00, 0x06, 0x00, 0x01,
+                    0x00, 0x09, 0x10, 0x00, 0x00, 0x78, 0x76, 0x98, 0xfa,
+                ]
+                .to_vec(),
+                [0; 22].to_vec(),
+            ),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+            SpiTransaction::transfer([0, 0, 0, 0].to_vec(), [0, 0, 0x0f, 0].to_vec()),
+            SpiTransaction::transfer(
+                [0; 19].to_vec(),
+                [
+                    0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x06, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01,
+                    0x00, 0x00, 0x4a, 0x8c, 0x44, 0xf4,
+                ]
+                .to_vec(),
+            ),
+            SpiTransaction::transfer([0x7f, 0xff, 0x01, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+        ];
+
+        let mut bm = mock_driver(&expectations, &[false, false, false, false], &[]);
+        match bm.begin_enroll() {
+            Err(_x) => panic!("Function returned unexpected error"),
+            Ok(session) => drop(session),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn add_image_error_keeps_session() {
+        use super::*;
+        let begin = link_transactions(
+            &[
+                0x01, 0x00, 0x0e, 0x00, 0x08, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x00, 0x01, 0x00,
+                0x03, 0x00, 0x00, 0x00, 0x96, 0x3b, 0x49, 0x0d,
+            ],
+            &[
+                0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01, 0x00,
+                0x00, 0x46, 0xdd, 0xa8, 0xa9,
+            ],
+        );
+        let discard = link_transactions(
+            &[
+                0x01, 0x00, 0x0e, 0x00, 0x08, 0x00, 0x01, 0x00, 0x01, 0x00, 0x06, 0x00, 0x01, 0x00,
+                0x09, 0x10, 0x00, 0x00, 0x78, 0x76, 0x98, 0xfa,
+            ],
+            &[
+                0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x06, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01, 0x00,
+                0x00, 0x4a, 0x8c, 0x44, 0xf4,
+            ],
+        );
+        let mut expectations = begin;
+        // Capture is NACKed
+        expectations.push(SpiTransaction::transfer(
+            [
+                0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+                0x52, 0x7c, 0x2b, 0x55,
+            ]
+            .to_vec(),
+            [0; 18].to_vec(),
+        ));
+        expectations.push(SpiTransaction::transfer(
+            [0, 0, 0, 0].to_vec(),
+            [0x7f, 0xff, 0x00, 0x7f].to_vec(),
+        ));
+        expectations.extend(discard);
+
+        let mut bm = mock_driver(&expectations, &[false, false, false, false, false], &[]);
+        assert!(bm
+            .set_retry_policy(RetryPolicy {
+                resend: 0,
+                ..RetryPolicy::default()
+            })
+            .is_ok());
+        let session = match bm.begin_enroll() {
+            Ok(session) => session,
+            Err(_) => panic!("Enroll did not begin"),
+        };
+        match session.add_image(0) {
+            Err((session, Error::Nack)) => assert!(session.abort().is_ok()),
+            _ => panic!("Session not returned with the error"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn enroll_rejects_duplicate() {
+        use super::*;
+        let mut packages = enroll_packages(&[1]);
+        // Identify matches template 1
+        packages.push((
+            package(0x0003),
+            answer(0x0003, 0)
+                .add_arg_u8(ARG_MATCH, 1)
+                .add_arg_u16(ARG_ID, 1),
+        ));
+        // Template in RAM is dropped instead of saved
+        packages.push((package(0x0006).add_arg(0x1009), answer(0x0006, 0)));
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        let options = EnrollOptions {
+            reject_duplicates: true,
+        };
+        match bm.enroll_with(options, |_| {}) {
+            Err(Error::Duplicate { existing_id }) => assert_eq!(existing_id, 1),
+            _ => panic!("Duplicate finger was not rejected"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn capture_completes_on_irq() {
+        use super::*;
+        let expectations = link_transactions(
+            &[
+                0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+                0x52, 0x7c, 0x2b, 0x55,
+            ],
+            &[
+                0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01, 0x00,
+                0x00, 0x83, 0xe1, 0x25, 0x90,
+            ],
+        );
+        // ACK ready, no finger yet when polled, IRQ raised with the response
+        let mut bm = mock_driver(&expectations, &[false, true, false], &[]);
+        assert!(bm.start_capture(0).is_ok());
+        assert_eq!(bm.pending(), Some(FingerWait::Capture));
+        match bm.poll() {
+            Err(nb::Error::WouldBlock) => {}
+            _ => panic!("Completed without IRQ"),
+        }
+        match bm.get_version() {
+            Err(Error::Busy) => {}
+            _ => panic!("Command sent during finger wait"),
+        }
+        match bm.on_irq() {
+            Ok(Completion::Captured(0)) => {}
+            _ => panic!("Capture did not complete"),
+        }
+        assert_eq!(bm.pending(), None);
+        match bm.on_irq() {
+            Err(nb::Error::WouldBlock) => {}
+            _ => panic!("Completed twice"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn capture_reads_one_frame_per_irq() {
+        use super::*;
+        // Result argument in three frames, each on its own IRQ edge
+        let (expectations, irq) =
+            package_transactions(&capture_command(0), &answer(CMD_CAPTURE, 0), 4);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        assert!(bm.start_capture(0).is_ok());
+        for _ in 0..2 {
+            match bm.on_irq() {
+                Err(nb::Error::WouldBlock) => {}
+                _ => panic!("Completed before the last frame"),
+            }
+            assert_eq!(bm.pending(), Some(FingerWait::Capture));
+        }
+        match bm.on_irq() {
+            Ok(Completion::Captured(0)) => {}
+            _ => panic!("Capture did not complete"),
+        }
+        assert_eq!(bm.pending(), None);
+
+        mock_done(bm);
+    }
+
+    #[cfg(feature = "alloc")]
+    fn install_idle_hook<F: FnMut() -> ::IdleAction + 'static>(bm: &mut MockBmLite, idle: F) {
+        bm.set_idle_hook(self::std::boxed::Box::new(idle));
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    fn install_idle_hook<F: FnMut() -> ::IdleAction + 'static>(bm: &mut MockBmLite, idle: F) {
+        bm.set_idle_hook(self::std::boxed::Box::leak(self::std::boxed::Box::new(
+            idle,
+        )));
+    }
+
+    #[test]
+    fn idle_hook_cancels_capture() {
+        use super::*;
+        use core::cell::Cell;
+        use tests::std::rc::Rc;
+
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let idle = move || {
+            counted.set(counted.get() + 1);
+            if counted.get() <= 2 {
+                IdleAction::Continue
+            } else {
+                IdleAction::Cancel
+            }
+        };
+
+        let mut expectations = link_transactions(
+            &[
+                0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+                0x52, 0x7c, 0x2b, 0x55,
+            ],
+            &[
+                0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01, 0x00,
+                0x00, 0x83, 0xe1, 0x25, 0x90,
+            ],
+        );
+        // Command and ACK, no finger comes
+        expectations.truncate(2);
+        let mut bm = mock_driver(&expectations, &[false, true, true, true], &[]);
+        install_idle_hook(&mut bm, idle);
+        match bm.capture(0) {
+            Err(Error::Cancelled) => {}
+            _ => panic!("Capture was not cancelled"),
+        }
+        assert_eq!(calls.get(), 3);
+        assert_eq!(bm.link_failures, 0);
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn idle_hook_cancels_boot_wait() {
+        use super::*;
+        use core::cell::Cell;
+        use tests::std::rc::Rc;
+
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let idle = move || {
+            counted.set(counted.get() + 1);
+            match counted.get() {
+                5 => IdleAction::Cancel,
+                _ => IdleAction::Continue,
+            }
+        };
+
+        // IRQ stays low after reset
+        let mut bm = mock_driver(&[], &[true; 5], &[false, true]);
+        install_idle_hook(&mut bm, idle);
+        let mut delay = DelayCount(0);
+        match bm.reset(&mut delay) {
+            Err(Error::Cancelled) => {}
+            _ => panic!("Boot wait was not cancelled"),
+        }
+        assert_eq!(calls.get(), 5);
+        assert_eq!(delay.0, RESET_HOLD_MS + 5);
+
+        mock_done(bm);
+    }
 }
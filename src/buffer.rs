@@ -0,0 +1,172 @@
+//!
+//! ## Package buffers
+//!
+//! Frames and application packages are built and parsed in `Bytes`. With
+//! the default `alloc` feature that is a `Vec<u8>`. Without it the driver
+//! needs no heap at all and `Bytes` is an `ArrayBuf` of `BUF_SIZE` bytes,
+//! large enough for one full link frame. Packages that do not fit are
+//! refused with `Error::BufferFull`.
+//!
+//! Responses are read into one buffer held by the driver and parsed where
+//! they lie, only what a command returns is copied out of it.
+//!
+
+use core::ops::{Deref, DerefMut};
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use MAX_LINK_SIZE;
+
+/// Size of `ArrayBuf` used as `Bytes`, one link frame with its header
+pub const BUF_SIZE: usize = MAX_LINK_SIZE as usize + 8;
+
+#[cfg(feature = "alloc")]
+pub type Bytes = Vec<u8>;
+#[cfg(not(feature = "alloc"))]
+pub type Bytes = ArrayBuf<BUF_SIZE>;
+
+/// Fixed capacity byte buffer with the parts of the `Vec` API the driver
+/// uses. Writes past the end are dropped and remembered, see `overflowed()`.
+#[derive(Clone)]
+pub struct ArrayBuf<const N: usize> {
+    data: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+impl<const N: usize> ArrayBuf<N> {
+    pub fn new() -> Self {
+        ArrayBuf {
+            data: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Same as `new()`, the capacity is always `N`
+    pub fn with_capacity(_capacity: usize) -> Self {
+        Self::new()
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.len < N {
+            self.data[self.len] = byte;
+            self.len += 1;
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        let room = N - self.len;
+        let n = bytes.len().min(room);
+        self.data[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+        if n < bytes.len() {
+            self.overflow = true;
+        }
+    }
+
+    /// Grow to `len` bytes filled with `value` or truncate to it
+    pub fn resize(&mut self, len: usize, value: u8) {
+        if len > N {
+            self.overflow = true;
+        }
+        let len = len.min(N);
+        if len > self.len {
+            self.data[self.len..len].fill(value);
+        }
+        self.len = len;
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.overflow = false;
+    }
+
+    /// Some data did not fit and was dropped
+    pub fn overflowed(&self) -> bool {
+        self.overflow
+    }
+}
+
+impl<const N: usize> Default for ArrayBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for ArrayBuf<N> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl<const N: usize> DerefMut for ArrayBuf<N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+impl<const N: usize> PartialEq for ArrayBuf<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for ArrayBuf<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        self[..].fmt(f)
+    }
+}
+
+/// True if `bytes` lost data, a `Vec` never does
+#[cfg(feature = "alloc")]
+pub(crate) fn overflowed(_bytes: &Bytes) -> bool {
+    false
+}
+#[cfg(not(feature = "alloc"))]
+pub(crate) fn overflowed(bytes: &Bytes) -> bool {
+    bytes.overflowed()
+}
+
+/// True if `n` more bytes fit in `bytes`, a `Vec` grows as needed
+#[cfg(feature = "alloc")]
+pub(crate) fn has_room(_bytes: &Bytes, _n: usize) -> bool {
+    true
+}
+#[cfg(not(feature = "alloc"))]
+pub(crate) fn has_room(bytes: &Bytes, n: usize) -> bool {
+    n <= bytes.capacity() - bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn array_buf_drops_overflow() {
+        let mut buf: ArrayBuf<4> = ArrayBuf::new();
+        buf.extend_from_slice(&[1, 2, 3]);
+        assert!(!buf.overflowed());
+        buf.extend_from_slice(&[4, 5]);
+        buf.push(6);
+        assert!(buf.overflowed());
+        assert_eq!(&buf[..], &[1, 2, 3, 4]);
+        buf.truncate(2);
+        buf[1] = 9;
+        assert_eq!(&buf[..], &[1, 9]);
+        buf.resize(3, 7);
+        assert_eq!(&buf[..], &[1, 9, 7]);
+    }
+}
@@ -0,0 +1,69 @@
+//!
+//! ## embedded-hal 0.2 compatibility
+//!
+//! The driver is written against the embedded-hal 1.0 traits. `Compat`
+//! wraps 0.2 pins and delays so they can be used with it. Pin errors are
+//! reported as `ErrorKind::Other`, pins implementing the old infallible
+//! traits never fail. For the SPI bus use `ExclusiveDevice` or
+//! `SharedDevice`, they take a 0.2 bus and chip select.
+//!
+//! `BmLite::new()` wraps everything itself, so 0.2 code only needs
+//! `Compat` for the delay passed to `reset()`.
+//!
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{ErrorKind, ErrorType, InputPin, OutputPin};
+use embedded_hal_02::blocking::delay::DelayMs;
+use embedded_hal_02::digital::v2;
+
+/// embedded-hal 0.2 pin or delay used as its 1.0 counterpart
+pub struct Compat<T>(T);
+
+impl<T> Compat<T> {
+    pub fn new(inner: T) -> Self {
+        Compat(inner)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> ErrorType for Compat<T> {
+    type Error = ErrorKind;
+}
+
+impl<T: v2::OutputPin> OutputPin for Compat<T> {
+    fn set_low(&mut self) -> Result<(), ErrorKind> {
+        self.0.set_low().map_err(|_| ErrorKind::Other)
+    }
+
+    fn set_high(&mut self) -> Result<(), ErrorKind> {
+        self.0.set_high().map_err(|_| ErrorKind::Other)
+    }
+}
+
+impl<T: v2::InputPin> InputPin for Compat<T> {
+    fn is_high(&mut self) -> Result<bool, ErrorKind> {
+        self.0.is_high().map_err(|_| ErrorKind::Other)
+    }
+
+    fn is_low(&mut self) -> Result<bool, ErrorKind> {
+        self.0.is_low().map_err(|_| ErrorKind::Other)
+    }
+}
+
+/// Delays shorter than a ms are rounded up to one
+impl<T: DelayMs<u32>> DelayNs for Compat<T> {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.delay_ms(ns.div_ceil(1_000_000));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.0.delay_ms(us.div_ceil(1_000));
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.0.delay_ms(ms);
+    }
+}
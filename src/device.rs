@@ -0,0 +1,468 @@
+//!
+//! ## SPI device
+//!
+//! The driver talks to the sensor through a `SensorSpi`, one call per
+//! chip selected transfer. Every embedded-hal 1.0 `SpiDevice` is one, so
+//! the devices of `embedded-hal-bus` work for exclusive and shared buses.
+//!
+//! For embedded-hal 0.2 buses `ExclusiveDevice` owns the bus and the chip
+//! select pin, this is what `BmLite::new()` uses. `SharedDevice` borrows a
+//! bus shared with other devices in a `RefCell` and holds it only for the
+//! duration of each transfer.
+//!
+//! Buses shared with interrupt handlers need a lock that `RefCell` does
+//! not give, implement `SensorSpi` on top of the platform mutex for those.
+//!
+
+use core::cell::RefCell;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::{Operation, SpiDevice};
+use embedded_hal_02::blocking::spi::Transfer;
+use embedded_hal_02::digital::v2::OutputPin;
+
+/// Most chunks an embedded-hal 1.0 `SpiDevice` splits a frame in without
+/// the `alloc` feature, see `DeviceError::TooManyChunks`
+pub const MAX_CHUNKS: usize = 32;
+
+/// SPI bus and chip select of the sensor
+pub trait SensorSpi {
+    type Error;
+
+    /// Transfer `buf` in place with chip select asserted, bus locked
+    /// for the whole transfer
+    fn transaction(&mut self, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Transfer `buf` in place as `transaction()`, split in transfers of
+    /// at most `chunk` bytes with a pause of `pause_ns` between them.
+    /// Chip select stays asserted throughout.
+    fn chunked_transaction(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        pause_ns: u32,
+    ) -> Result<(), Self::Error>;
+
+    /// `chunked_transaction()` calling `idle` while it waits for the
+    /// transfer, for devices that move data without the CPU
+    fn transaction_with_idle(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        pause_ns: u32,
+        _idle: &mut dyn FnMut(),
+    ) -> Result<(), Self::Error> {
+        self.chunked_transaction(buf, chunk, pause_ns)
+    }
+
+    /// False if `chunked_transaction()` cannot pause between chunks
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    /// Memory the device moves without a copy, e.g. a DMA buffer. The
+    /// driver builds and reads frames that fit in it in place and moves
+    /// them with `buffer_transaction()`. None for most devices.
+    fn frame_buffer(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// `frame_buffer()` to build a frame in
+    fn frame_buffer_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Transfer the first `len` bytes of `frame_buffer()` in place as
+    /// `transaction_with_idle()`. Only called on devices with a frame
+    /// buffer, which implement it.
+    fn buffer_transaction(
+        &mut self,
+        _len: usize,
+        _chunk: usize,
+        _pause_ns: u32,
+        _idle: &mut dyn FnMut(),
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Operations moving `buf` in chunks of `chunk` bytes, `pause_ns` apart
+fn chunk_ops(
+    buf: &mut [u8],
+    chunk: usize,
+    pause_ns: u32,
+) -> impl Iterator<Item = Operation<'_, u8>> {
+    buf.chunks_mut(chunk)
+        .enumerate()
+        .flat_map(move |(i, part)| {
+            let pause = if i > 0 && pause_ns > 0 {
+                Some(Operation::DelayNs(pause_ns))
+            } else {
+                None
+            };
+            pause
+                .into_iter()
+                .chain(core::iter::once(Operation::TransferInPlace(part)))
+        })
+}
+
+impl<T: SpiDevice> SensorSpi for T {
+    type Error = DeviceError<T::Error>;
+
+    fn transaction(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.transfer_in_place(buf).map_err(DeviceError::Spi)
+    }
+
+    /// All chunks go in one `SpiDevice` transaction
+    #[cfg(feature = "alloc")]
+    fn chunked_transaction(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        pause_ns: u32,
+    ) -> Result<(), Self::Error> {
+        if chunk == 0 || buf.len() <= chunk {
+            return SensorSpi::transaction(self, buf);
+        }
+        let mut ops: Vec<Operation<u8>> = chunk_ops(buf, chunk, pause_ns).collect();
+        SpiDevice::transaction(self, &mut ops).map_err(DeviceError::Spi)
+    }
+
+    /// All chunks go in one `SpiDevice` transaction, at most `MAX_CHUNKS`
+    #[cfg(not(feature = "alloc"))]
+    fn chunked_transaction(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        pause_ns: u32,
+    ) -> Result<(), Self::Error> {
+        if chunk == 0 || buf.len() <= chunk {
+            return SensorSpi::transaction(self, buf);
+        }
+        if buf.len().div_ceil(chunk) > MAX_CHUNKS {
+            return Err(DeviceError::TooManyChunks);
+        }
+        let mut ops: [Operation<u8>; 2 * MAX_CHUNKS - 1] =
+            core::array::from_fn(|_| Operation::DelayNs(0));
+        let mut n = 0;
+        for (slot, op) in ops.iter_mut().zip(chunk_ops(buf, chunk, pause_ns)) {
+            *slot = op;
+            n += 1;
+        }
+        SpiDevice::transaction(self, &mut ops[..n]).map_err(DeviceError::Spi)
+    }
+}
+
+/// Errors of the SPI devices
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceError<E> {
+    /// Bus is in use by another device
+    Busy,
+    Spi(E),
+    /// Chip select pin could not be set
+    ChipSelect,
+    /// Frame needs more than `MAX_CHUNKS` chunks, only for embedded-hal 1.0
+    /// devices without the `alloc` feature
+    TooManyChunks,
+}
+
+/// Delay for devices that never pause between chunks
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// Chunked transfer with chip select asserted
+fn chunked<SPI, CS, D>(
+    spi: &mut SPI,
+    cs: &mut CS,
+    delay: &mut D,
+    buf: &mut [u8],
+    chunk: usize,
+    pause_ns: u32,
+) -> Result<(), DeviceError<SPI::Error>>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+    D: DelayNs,
+{
+    let chunk = if chunk == 0 { buf.len().max(1) } else { chunk };
+    cs.set_low().map_err(|_| DeviceError::ChipSelect)?;
+    let mut res = Ok(());
+    for (i, part) in buf.chunks_mut(chunk).enumerate() {
+        if i > 0 && pause_ns > 0 {
+            delay.delay_ns(pause_ns);
+        }
+        res = spi.transfer(part).map(|_| ()).map_err(DeviceError::Spi);
+        if res.is_err() {
+            break;
+        }
+    }
+    // Release chip select even if the transfer failed
+    let released = cs.set_high().map_err(|_| DeviceError::ChipSelect);
+    res.and(released)
+}
+
+/// embedded-hal 0.2 SPI bus owned by the sensor. Pauses between chunks
+/// need a delay, see `with_delay()`.
+pub struct ExclusiveDevice<SPI, CS, D = NoDelay> {
+    spi: SPI,
+    cs: CS,
+    delay: D,
+    // Built with a delay of the application
+    paced: bool,
+}
+
+impl<SPI, CS> ExclusiveDevice<SPI, CS>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    pub fn new(spi: SPI, cs: CS) -> Self {
+        ExclusiveDevice {
+            spi,
+            cs,
+            delay: NoDelay,
+            paced: false,
+        }
+    }
+}
+
+impl<SPI, CS, D> ExclusiveDevice<SPI, CS, D>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+    D: DelayNs,
+{
+    /// Device pausing between chunks with `delay`
+    pub fn with_delay(spi: SPI, cs: CS, delay: D) -> Self {
+        ExclusiveDevice {
+            spi,
+            cs,
+            delay,
+            paced: true,
+        }
+    }
+
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    pub fn into_parts(self) -> (SPI, CS, D) {
+        (self.spi, self.cs, self.delay)
+    }
+}
+
+impl<SPI, CS, D> SensorSpi for ExclusiveDevice<SPI, CS, D>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+    D: DelayNs,
+{
+    type Error = DeviceError<SPI::Error>;
+
+    fn transaction(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.chunked_transaction(buf, 0, 0)
+    }
+
+    fn chunked_transaction(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        pause_ns: u32,
+    ) -> Result<(), Self::Error> {
+        chunked(
+            &mut self.spi,
+            &mut self.cs,
+            &mut self.delay,
+            buf,
+            chunk,
+            pause_ns,
+        )
+    }
+
+    fn can_pause(&self) -> bool {
+        self.paced
+    }
+}
+
+/// embedded-hal 0.2 SPI bus shared with other devices, see module
+/// documentation. Pauses between chunks need a delay, see `with_delay()`.
+pub struct SharedDevice<'a, SPI: 'a, CS, D = NoDelay> {
+    bus: &'a RefCell<SPI>,
+    cs: CS,
+    delay: D,
+    // Built with a delay of the application
+    paced: bool,
+}
+
+impl<'a, SPI, CS> SharedDevice<'a, SPI, CS>
+where
+    SPI: Transfer<u8> + 'a,
+    CS: OutputPin,
+{
+    pub fn new(bus: &'a RefCell<SPI>, cs: CS) -> Self {
+        SharedDevice {
+            bus,
+            cs,
+            delay: NoDelay,
+            paced: false,
+        }
+    }
+}
+
+impl<'a, SPI, CS, D> SharedDevice<'a, SPI, CS, D>
+where
+    SPI: Transfer<u8> + 'a,
+    CS: OutputPin,
+    D: DelayNs,
+{
+    /// Device pausing between chunks with `delay`
+    pub fn with_delay(bus: &'a RefCell<SPI>, cs: CS, delay: D) -> Self {
+        SharedDevice {
+            bus,
+            cs,
+            delay,
+            paced: true,
+        }
+    }
+
+    pub fn release(self) -> CS {
+        self.cs
+    }
+
+    pub fn into_parts(self) -> (CS, D) {
+        (self.cs, self.delay)
+    }
+}
+
+impl<'a, SPI, CS, D> SensorSpi for SharedDevice<'a, SPI, CS, D>
+where
+    SPI: Transfer<u8> + 'a,
+    CS: OutputPin,
+    D: DelayNs,
+{
+    type Error = DeviceError<SPI::Error>;
+
+    fn transaction(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.chunked_transaction(buf, 0, 0)
+    }
+
+    fn chunked_transaction(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        pause_ns: u32,
+    ) -> Result<(), Self::Error> {
+        let mut bus = self.bus.try_borrow_mut().map_err(|_| DeviceError::Busy)?;
+        chunked(
+            &mut *bus,
+            &mut self.cs,
+            &mut self.delay,
+            buf,
+            chunk,
+            pause_ns,
+        )
+    }
+
+    fn can_pause(&self) -> bool {
+        self.paced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate embedded_hal_mock;
+    use self::embedded_hal_mock::gpio::DigitalIOMock;
+    use self::embedded_hal_mock::spi::{Mock as SpiMock, Transaction as SpiTransaction};
+    use super::*;
+
+    #[test]
+    fn shared_bus_locked_per_transaction() {
+        let expectations = [SpiTransaction::transfer([1, 2].to_vec(), [3, 4].to_vec())];
+        let bus = RefCell::new(SpiMock::new(&expectations));
+        let cs = DigitalIOMock::new("spi-cs", [false, true].to_vec());
+        let mut dev = SharedDevice::new(&bus, cs);
+
+        let mut buf = [1, 2];
+        assert!(dev.transaction(&mut buf).is_ok());
+        assert_eq!(buf, [3, 4]);
+        {
+            // Another device is in the middle of a transfer
+            let _other = bus.borrow_mut();
+            match dev.transaction(&mut buf) {
+                Err(DeviceError::Busy) => {}
+                _ => panic!("Transfer on a busy bus"),
+            }
+        }
+        let _cs = dev.release();
+        bus.borrow_mut().done();
+    }
+
+    struct CountDelay(u32);
+    impl DelayNs for CountDelay {
+        fn delay_ns(&mut self, ns: u32) {
+            self.0 += ns;
+        }
+    }
+
+    #[test]
+    fn chunks_share_chip_select() {
+        let expectations = [
+            SpiTransaction::transfer([1, 2].to_vec(), [5, 6].to_vec()),
+            SpiTransaction::transfer([3, 4].to_vec(), [7, 8].to_vec()),
+            SpiTransaction::transfer([9].to_vec(), [10].to_vec()),
+        ];
+        let spi = SpiMock::new(&expectations);
+        let cs = DigitalIOMock::new("spi-cs", [false, true].to_vec());
+        let mut dev = ExclusiveDevice::with_delay(spi, cs, CountDelay(0));
+
+        let mut buf = [1, 2, 3, 4, 9];
+        assert!(dev.chunked_transaction(&mut buf, 2, 500).is_ok());
+        assert_eq!(buf, [5, 6, 7, 8, 10]);
+        let (mut spi, _cs, delay) = dev.into_parts();
+        assert_eq!(delay.0, 1000);
+        spi.done();
+    }
+
+    // embedded-hal 1.0 device noting the operations of each transaction
+    struct Recorder(u32, u32);
+    impl embedded_hal::spi::ErrorType for Recorder {
+        type Error = embedded_hal::spi::ErrorKind;
+    }
+    impl SpiDevice for Recorder {
+        fn transaction(&mut self, ops: &mut [Operation<u8>]) -> Result<(), Self::Error> {
+            for op in ops {
+                match *op {
+                    Operation::TransferInPlace(ref mut buf) => self.0 += buf.len() as u32,
+                    Operation::DelayNs(ns) => self.1 += ns,
+                    _ => return Err(embedded_hal::spi::ErrorKind::Other),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn spi_device_keeps_chunk_size() {
+        let mut dev = Recorder(0, 0);
+        let mut buf = [0; 2 * MAX_CHUNKS];
+        assert!(dev.chunked_transaction(&mut buf[..5], 2, 10).is_ok());
+        assert_eq!((dev.0, dev.1), (5, 20));
+
+        // One byte chunks, more than MAX_CHUNKS of them
+        let mut dev = Recorder(0, 0);
+        let res = dev.chunked_transaction(&mut buf, 1, 1);
+        if cfg!(feature = "alloc") {
+            assert!(res.is_ok());
+            assert_eq!((dev.0, dev.1), (buf.len() as u32, buf.len() as u32 - 1));
+        } else {
+            assert_eq!(res, Err(DeviceError::TooManyChunks));
+            assert_eq!((dev.0, dev.1), (0, 0));
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//!
+//! ## DMA transfers
+//!
+//! `DmaDevice` is a `SensorSpi` that moves frames with DMA through
+//! `DmaTransfer`, typically implemented on top of the HAL's DMA transfer
+//! type. It owns a `'static` buffer that the driver builds and reads
+//! frames in, so they move without a copy. Frames larger than the buffer
+//! and other memory are streamed through it in pieces, each copied in and
+//! out. Chip select is held for the whole frame and pieces are no larger
+//! than the link chunk size. While a piece is moved the driver's idle hook
+//! runs instead of a busy wait.
+//!
+
+use core::ops::Range;
+
+use SensorSpi;
+
+/// Buffer handed back by a finished transfer and its outcome
+pub type DmaDone<E> = (&'static mut [u8], Result<(), E>);
+
+/// SPI transfers done by DMA, chip select set separately
+pub trait DmaTransfer {
+    type Error;
+
+    /// Assert or release chip select, held across the transfers of a frame
+    fn select(&mut self, selected: bool) -> Result<(), Self::Error>;
+
+    /// Start an in place transfer of `range` of `buf`
+    fn start(&mut self, buf: &'static mut [u8], range: Range<usize>);
+
+    /// None while the transfer runs, then the buffer and the outcome
+    fn poll(&mut self) -> Option<DmaDone<Self::Error>>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmaError<E> {
+    /// DMA buffer is empty, nothing can be moved through it
+    NoBuffer,
+    Transfer(E),
+}
+
+pub struct DmaDevice<D> {
+    dma: D,
+    buf: &'static mut [u8],
+}
+
+impl<D: DmaTransfer> DmaDevice<D> {
+    pub fn new(dma: D, buf: &'static mut [u8]) -> Self {
+        DmaDevice { dma, buf }
+    }
+
+    pub fn release(self) -> (D, &'static mut [u8]) {
+        (self.dma, self.buf)
+    }
+
+    /// Copy `buf` through the DMA buffer in pieces of at most `chunk`
+    /// bytes, 0 for no limit, with chip select asserted throughout
+    fn stream(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        idle: &mut dyn FnMut(),
+    ) -> Result<(), DmaError<D::Error>> {
+        if self.buf.is_empty() {
+            return Err(DmaError::NoBuffer);
+        }
+        let piece = match chunk {
+            0 => self.buf.len(),
+            chunk => chunk.min(self.buf.len()),
+        };
+        self.selected(|dev| {
+            for part in buf.chunks_mut(piece) {
+                let len = part.len();
+                dev.buf[..len].copy_from_slice(part);
+                dev.piece(0..len, idle)?;
+                part.copy_from_slice(&dev.buf[..len]);
+            }
+            Ok(())
+        })
+    }
+
+    /// Move the first `len` bytes of the DMA buffer in place, in pieces
+    /// of at most `chunk` bytes, 0 for no limit
+    fn stream_buffer(
+        &mut self,
+        len: usize,
+        chunk: usize,
+        idle: &mut dyn FnMut(),
+    ) -> Result<(), DmaError<D::Error>> {
+        let piece = match chunk {
+            0 => len,
+            chunk => chunk,
+        };
+        self.selected(|dev| {
+            let mut at = 0;
+            while at < len {
+                let end = len.min(at + piece);
+                dev.piece(at..end, idle)?;
+                at = end;
+            }
+            Ok(())
+        })
+    }
+
+    /// Run `transfers` with chip select asserted, released even if they
+    /// fail
+    fn selected<F>(&mut self, transfers: F) -> Result<(), DmaError<D::Error>>
+    where
+        F: FnOnce(&mut Self) -> Result<(), DmaError<D::Error>>,
+    {
+        self.dma.select(true).map_err(DmaError::Transfer)?;
+        let res = transfers(self);
+        let released = self.dma.select(false).map_err(DmaError::Transfer);
+        res.and(released)
+    }
+
+    /// One DMA transfer of `range` of the buffer, `idle` is called until
+    /// it is done
+    fn piece(
+        &mut self,
+        range: Range<usize>,
+        idle: &mut dyn FnMut(),
+    ) -> Result<(), DmaError<D::Error>> {
+        let dma_buf = core::mem::take(&mut self.buf);
+        self.dma.start(dma_buf, range);
+        let (dma_buf, res) = loop {
+            if let Some(done) = self.dma.poll() {
+                break done;
+            }
+            idle();
+        };
+        self.buf = dma_buf;
+        res.map_err(DmaError::Transfer)
+    }
+}
+
+impl<D: DmaTransfer> SensorSpi for DmaDevice<D> {
+    type Error = DmaError<D::Error>;
+
+    fn transaction(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.stream(buf, 0, &mut || {})
+    }
+
+    /// DMA cannot pause between chunks, `pause_ns` is not used
+    fn chunked_transaction(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        _pause_ns: u32,
+    ) -> Result<(), Self::Error> {
+        self.stream(buf, chunk, &mut || {})
+    }
+
+    fn transaction_with_idle(
+        &mut self,
+        buf: &mut [u8],
+        chunk: usize,
+        _pause_ns: u32,
+        idle: &mut dyn FnMut(),
+    ) -> Result<(), Self::Error> {
+        self.stream(buf, chunk, idle)
+    }
+
+    fn can_pause(&self) -> bool {
+        false
+    }
+
+    fn frame_buffer(&self) -> Option<&[u8]> {
+        Some(&*self.buf)
+    }
+
+    fn frame_buffer_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut *self.buf)
+    }
+
+    /// DMA cannot pause between chunks, `pause_ns` is not used
+    fn buffer_transaction(
+        &mut self,
+        len: usize,
+        chunk: usize,
+        _pause_ns: u32,
+        idle: &mut dyn FnMut(),
+    ) -> Result<(), Self::Error> {
+        self.stream_buffer(len, chunk, idle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate embedded_hal_mock;
+    extern crate std;
+    use self::embedded_hal_mock::gpio::DigitalIOMock;
+    use self::std::boxed::Box;
+    use self::std::rc::Rc;
+    use self::std::vec::Vec;
+    use super::*;
+    use core::cell::Cell;
+    use {BmLite, Compat, IdleAction, LinkConfig};
+
+    // Answers every byte inverted on the second poll
+    struct Inverter {
+        running: Option<(&'static mut [u8], Range<usize>)>,
+        busy: bool,
+        selects: u32,
+        polls: u32,
+    }
+
+    impl DmaTransfer for Inverter {
+        type Error = ();
+
+        fn select(&mut self, selected: bool) -> Result<(), ()> {
+            if selected {
+                self.selects += 1;
+            }
+            Ok(())
+        }
+
+        fn start(&mut self, buf: &'static mut [u8], range: Range<usize>) {
+            self.running = Some((buf, range));
+            self.busy = true;
+        }
+
+        fn poll(&mut self) -> Option<DmaDone<()>> {
+            self.polls += 1;
+            if self.busy {
+                self.busy = false;
+                return None;
+            }
+            self.running.take().map(|(buf, range)| {
+                for byte in &mut buf[range] {
+                    *byte = !*byte;
+                }
+                (buf, Ok(()))
+            })
+        }
+    }
+
+    #[test]
+    fn dma_streams_frames_larger_than_buffer() {
+        let buf: &'static mut [u8] = Box::leak(Box::new([0u8; 4]));
+        let dma = Inverter {
+            running: None,
+            busy: false,
+            selects: 0,
+            polls: 0,
+        };
+        let mut dev = DmaDevice::new(dma, buf);
+
+        let mut frame = [0x00, 0x7f, 0xff];
+        let mut idle = 0;
+        assert_eq!(
+            dev.transaction_with_idle(&mut frame, 0, 0, &mut || idle += 1),
+            Ok(())
+        );
+        assert_eq!(frame, [0xff, 0x80, 0x00]);
+        assert_eq!(idle, 1);
+        // Two pieces under one chip select
+        let mut frame = [1, 2, 3, 4, 5];
+        assert_eq!(dev.transaction(&mut frame), Ok(()));
+        assert_eq!(frame, [!1, !2, !3, !4, !5]);
+
+        let (dma, buf) = dev.release();
+        assert_eq!((dma.selects, dma.polls), (2, 6));
+        assert_eq!(buf.len(), 4);
+    }
+
+    // Bytes written and read under one chip select
+    type Transfer = (Vec<u8>, Vec<u8>);
+
+    // Plays back chip selected transfers in pieces of any size
+    struct ScriptDma {
+        script: Vec<Transfer>,
+        // Transfer under chip select and bytes of it moved
+        current: Option<(Transfer, usize)>,
+        running: Option<&'static mut [u8]>,
+        // Busy for one poll of every transfer
+        busy: bool,
+        starts: u32,
+    }
+
+    impl DmaTransfer for ScriptDma {
+        type Error = ();
+
+        fn select(&mut self, selected: bool) -> Result<(), ()> {
+            if selected {
+                assert!(!self.script.is_empty(), "Unexpected transfer");
+                self.current = Some((self.script.remove(0), 0));
+            } else if let Some(((written, _), moved)) = self.current.take() {
+                assert_eq!(moved, written.len());
+            }
+            Ok(())
+        }
+
+        fn start(&mut self, buf: &'static mut [u8], range: Range<usize>) {
+            let &mut ((ref written, ref read), ref mut moved) = self.current.as_mut().unwrap();
+            let len = range.len();
+            assert_eq!(&buf[range.clone()], &written[*moved..*moved + len]);
+            buf[range].copy_from_slice(&read[*moved..*moved + len]);
+            *moved += len;
+            self.running = Some(buf);
+            self.starts += 1;
+        }
+
+        fn poll(&mut self) -> Option<DmaDone<()>> {
+            self.busy = !self.busy;
+            if self.busy {
+                return None;
+            }
+            self.running.take().map(|buf| (buf, Ok(())))
+        }
+    }
+
+    // Transfers of a command answered in one frame, as `link_transactions()`
+    fn link_script(command: &[u8], response: &[u8]) -> Vec<Transfer> {
+        let size = response.len() - 4;
+        [
+            (command.to_vec(), command.iter().map(|_| 0).collect()),
+            ([0, 0, 0, 0].to_vec(), [0x7f, 0xff, 0x01, 0x7f].to_vec()),
+            (
+                [0, 0, 0, 0].to_vec(),
+                [0, 0, size as u8, (size >> 8) as u8].to_vec(),
+            ),
+            (response.iter().map(|_| 0).collect(), response.to_vec()),
+            ([0x7f, 0xff, 0x01, 0x7f].to_vec(), [0, 0, 0, 0].to_vec()),
+        ]
+        .to_vec()
+    }
+
+    // Capture with no timeout and its response
+    const CAPTURE: (&[u8], &[u8]) = (
+        &[
+            0x01, 0x00, 0x0a, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x52, 0x7c, 0x2b, 0x55,
+        ],
+        &[
+            0x09, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x20, 0x01, 0x00,
+            0x00, 0x83, 0xe1, 0x25, 0x90,
+        ],
+    );
+
+    #[test]
+    fn bmlite_over_dma() {
+        let capture = CAPTURE;
+        let mut script = link_script(capture.0, capture.1);
+        script.extend(link_script(capture.0, capture.1));
+        let dma = ScriptDma {
+            script,
+            current: None,
+            running: None,
+            busy: false,
+            starts: 0,
+        };
+        // Smaller than every frame but the ACKs
+        let buf: &'static mut [u8] = Box::leak(Box::new([0u8; 8]));
+        let irq = DigitalIOMock::new("spi-irq", [false; 4].to_vec());
+        let rst = DigitalIOMock::new("spi-rst", [].to_vec());
+        let mut bm =
+            BmLite::with_device(DmaDevice::new(dma, buf), Compat::new(rst), Compat::new(irq));
+        let idles = Rc::new(Cell::new(0));
+        let counted = idles.clone();
+        let hook = move || {
+            counted.set(counted.get() + 1);
+            IdleAction::Continue
+        };
+        #[cfg(feature = "alloc")]
+        bm.set_idle_hook(Box::new(hook));
+        #[cfg(not(feature = "alloc"))]
+        bm.set_idle_hook(Box::leak(Box::new(hook)));
+
+        assert!(bm.capture(0).is_ok());
+        // Chunks smaller than the buffer are kept
+        let config = LinkConfig {
+            chunk_size: 4,
+            chunk_pause_ns: 0,
+        };
+        assert!(bm.set_link_config(config).is_ok());
+        assert!(bm.capture(0).is_ok());
+
+        let (dev, _rst, _irq) = bm.release();
+        let (dma, _buf) = dev.release();
+        assert!(dma.script.is_empty());
+        assert_eq!(dma.starts, (3 + 1 + 1 + 3 + 1) + (5 + 1 + 1 + 5 + 1));
+        // Once for every busy poll
+        assert_eq!(idles.get(), dma.starts);
+    }
+
+    #[test]
+    fn bmlite_builds_frames_in_dma_buffer() {
+        let dma = ScriptDma {
+            script: link_script(CAPTURE.0, CAPTURE.1),
+            current: None,
+            running: None,
+            busy: false,
+            starts: 0,
+        };
+        let buf: &'static mut [u8] = Box::leak(Box::new([0u8; 64]));
+        let irq = DigitalIOMock::new("spi-irq", [false; 2].to_vec());
+        let rst = DigitalIOMock::new("spi-rst", [].to_vec());
+        let mut bm =
+            BmLite::with_device(DmaDevice::new(dma, buf), Compat::new(rst), Compat::new(irq));
+
+        assert!(bm.capture(0).is_ok());
+
+        let (dev, _rst, _irq) = bm.release();
+        let (dma, buf) = dev.release();
+        assert!(dma.script.is_empty());
+        assert_eq!(dma.starts, 5);
+        // Response was read in place, only the ACK after it copied over
+        // its link header
+        assert_eq!(&buf[4..19], &CAPTURE.1[4..]);
+    }
+}
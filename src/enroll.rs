@@ -0,0 +1,186 @@
+//!
+//! ## Enrollment session
+//!
+//! `BmLite::begin_enroll()` starts a new template on the sensor and returns
+//! an `EnrollSession` borrowing the driver. Images are added until the
+//! sensor reports the template complete, only then can it be finished and
+//! saved. A session dropped before that is aborted on the sensor.
+//!
+
+use core::marker::PhantomData;
+
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use {AuditEvent, BmLite, EnrollOptions, Error, SensorSpi};
+
+/// Session state: images are still needed
+pub struct Collecting;
+/// Session state: sensor has all images it needs
+pub struct Complete;
+
+/// Enrollment in progress on the sensor. The state parameter tells which
+/// operations are valid, `Collecting` sessions take images and turn
+/// `Complete` once the sensor has enough of them.
+pub struct EnrollSession<'a, DEV, RST, IRQ, S>
+where
+    DEV: SensorSpi + 'a,
+    RST: OutputPin + 'a,
+    IRQ: InputPin + 'a,
+{
+    // None once the session is finished or aborted
+    bm: Option<&'a mut BmLite<DEV, RST, IRQ>>,
+    state: PhantomData<S>,
+}
+
+/// Result of `add_image()`. On error the session is handed back so the
+/// caller can try again or abort it.
+pub type AddImageResult<'a, DEV, RST, IRQ, E> =
+    Result<AddImage<'a, DEV, RST, IRQ>, (EnrollSession<'a, DEV, RST, IRQ, Collecting>, Error<E>)>;
+
+/// Outcome of adding an image to a collecting session
+pub enum AddImage<'a, DEV, RST, IRQ>
+where
+    DEV: SensorSpi + 'a,
+    RST: OutputPin + 'a,
+    IRQ: InputPin + 'a,
+{
+    /// Image added, `remaining` more are needed
+    More(EnrollSession<'a, DEV, RST, IRQ, Collecting>, u32),
+    /// Image was not used, second value is the sensor result code
+    Rejected(EnrollSession<'a, DEV, RST, IRQ, Collecting>, u32),
+    /// Image added and the template is complete
+    Complete(EnrollSession<'a, DEV, RST, IRQ, Complete>),
+}
+
+impl<DEV, RST, IRQ, E> BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi<Error = E>,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    /// Start enrolling a new template
+    pub fn begin_enroll<'a>(
+        &'a mut self,
+    ) -> Result<EnrollSession<'a, DEV, RST, IRQ, Collecting>, Error<E>> {
+        self.enroll_state(0x03)?; //begin
+        Ok(EnrollSession {
+            bm: Some(self),
+            state: PhantomData,
+        })
+    }
+}
+
+impl<'a, DEV, RST, IRQ, E, S> EnrollSession<'a, DEV, RST, IRQ, S>
+where
+    DEV: SensorSpi<Error = E> + 'a,
+    RST: OutputPin + 'a,
+    IRQ: InputPin + 'a,
+{
+    fn driver(&mut self) -> &mut BmLite<DEV, RST, IRQ> {
+        self.bm.as_mut().expect("enroll session already closed")
+    }
+
+    fn into_state<T>(mut self) -> EnrollSession<'a, DEV, RST, IRQ, T> {
+        EnrollSession {
+            bm: self.bm.take(),
+            state: PhantomData,
+        }
+    }
+
+    /// Abort the enrollment and drop the partial template on the sensor
+    pub fn abort(mut self) -> Result<(), Error<E>> {
+        match self.bm.take() {
+            Some(bm) => bm.discard_template(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, DEV, RST, IRQ, E> EnrollSession<'a, DEV, RST, IRQ, Collecting>
+where
+    DEV: SensorSpi<Error = E> + 'a,
+    RST: OutputPin + 'a,
+    IRQ: InputPin + 'a,
+{
+    /// Wait for the finger to be lifted before the next image.
+    /// Timeout in ms but 0 waits forever
+    pub fn wait_finger_up(&mut self, timeout: u32) -> Result<u8, Error<E>> {
+        self.driver().waitfingerup(timeout)
+    }
+
+    /// Capture an image and add it to the template.
+    /// Timeout in ms but 0 waits forever. On error the session is returned
+    /// with it and is still collecting.
+    pub fn add_image(mut self, timeout: u32) -> AddImageResult<'a, DEV, RST, IRQ, E> {
+        let captured = match self.driver().capture(timeout) {
+            Ok(captured) => captured,
+            Err(e) => return Err((self, e)),
+        };
+        if captured != 0 {
+            return Ok(AddImage::Rejected(self, captured as u32));
+        }
+        // add image
+        let (result, remaining) = match self.driver().enroll_state(0x04) {
+            Ok(state) => state,
+            Err(e) => return Err((self, e)),
+        };
+        if result != 0 {
+            return Ok(AddImage::Rejected(self, result));
+        }
+        if remaining > 0 {
+            return Ok(AddImage::More(self, remaining));
+        }
+        Ok(AddImage::Complete(self.into_state()))
+    }
+}
+
+impl<'a, DEV, RST, IRQ, E> EnrollSession<'a, DEV, RST, IRQ, Complete>
+where
+    DEV: SensorSpi<Error = E> + 'a,
+    RST: OutputPin + 'a,
+    IRQ: InputPin + 'a,
+{
+    /// Finish the template and save it in sensor storage under `id`
+    pub fn finish(self, id: u16) -> Result<u32, Error<E>> {
+        self.finish_with(id, EnrollOptions::default())
+    }
+
+    /// Finish the template and save it under `id` unless `options` reject it.
+    /// A rejected template is dropped from the sensor.
+    pub fn finish_with(mut self, id: u16, options: EnrollOptions) -> Result<u32, Error<E>> {
+        self.driver().enroll_state(0x05)?; //done
+        if options.reject_duplicates {
+            // Template just finished is in RAM, match it against storage
+            match self.driver().do_identify() {
+                Ok(existing) => {
+                    self.driver().audit(AuditEvent::EnrollRejected {
+                        existing_id: existing,
+                    });
+                    return Err(Error::Duplicate {
+                        existing_id: existing,
+                    });
+                }
+                Err(Error::NoMatch) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.driver().do_savetemplate(id)?;
+        self.driver().audit(AuditEvent::Enrolled { id });
+        self.bm = None;
+        Ok(id as u32)
+    }
+}
+
+impl<'a, DEV, RST, IRQ, S> Drop for EnrollSession<'a, DEV, RST, IRQ, S>
+where
+    DEV: SensorSpi + 'a,
+    RST: OutputPin + 'a,
+    IRQ: InputPin + 'a,
+{
+    fn drop(&mut self) {
+        if let Some(bm) = self.bm.take() {
+            // Nothing left to report the error to
+            let _ = bm.discard_template();
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//!
+//! ## Interrupt driven finger waits
+//!
+//! `capture()` and `waitfingerup()` poll the IRQ pin until the user puts
+//! a finger on the sensor or lifts it. Firmware that would rather sleep
+//! starts the wait with `start_capture()` or `start_finger_up()` and calls
+//! `on_irq()` from the interrupt of the rising IRQ edge. It checks that IRQ
+//! is raised, reads one frame of the response and once the last is read
+//! returns the `Completion`. `WouldBlock` means the edge was spurious, not
+//! for the pending wait or more frames follow. Without an interrupt use
+//! `poll()` with `block!` or after waking up for other reasons.
+//!
+//! Other commands return `Error::Busy` while a wait is pending, `reset()`
+//! drops it.
+//!
+
+use embedded_hal::digital::{InputPin, OutputPin};
+use nb;
+
+use {capture_command, finger_result, finger_up_command, pin_error};
+use {BmLite, Error, SensorSpi, CMD_CAPTURE, CMD_WAIT_FINGER_UP};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FingerWait {
+    Capture,
+    FingerUp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Completion {
+    /// Image captured, result code as from `capture()`
+    Captured(u8),
+    /// Finger lifted, result code as from `waitfingerup()`
+    FingerUp(u8),
+}
+
+impl<DEV, RST, IRQ, E> BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi<Error = E>,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    /// Send a capture command and return without waiting for the finger.
+    /// Timeout in ms but 0 waits forever
+    pub fn start_capture(&mut self, timeout: u32) -> Result<(), Error<E>> {
+        self.link_send(&capture_command(timeout))?;
+        self.pending = Some(FingerWait::Capture);
+        Ok(())
+    }
+
+    /// Send a wait for finger up command and return without waiting
+    pub fn start_finger_up(&mut self, timeout: u32) -> Result<(), Error<E>> {
+        self.link_send(&finger_up_command(timeout))?;
+        self.pending = Some(FingerWait::FingerUp);
+        Ok(())
+    }
+
+    /// Finger wait in progress, if any
+    pub fn pending(&self) -> Option<FingerWait> {
+        self.pending
+    }
+
+    /// Call on the rising edge of IRQ, see module documentation
+    pub fn on_irq(&mut self) -> nb::Result<Completion, Error<E>> {
+        let (wait, cmd) = match self.pending {
+            Some(FingerWait::Capture) => (FingerWait::Capture, CMD_CAPTURE),
+            Some(FingerWait::FingerUp) => (FingerWait::FingerUp, CMD_WAIT_FINGER_UP),
+            None => return Err(nb::Error::WouldBlock),
+        };
+        // Spurious edge, reading now would wait for the sensor in here
+        if self.irq.is_low().map_err(pin_error)? {
+            return Err(nb::Error::WouldBlock);
+        }
+        // One frame per IRQ, the next is read on the next edge
+        let read = self.link_read(cmd);
+        if let Ok(false) = read {
+            return Err(nb::Error::WouldBlock);
+        }
+        // Response is read or lost, the wait is over either way
+        self.pending = None;
+        read?;
+        let result = finger_result(&self.rx, cmd)?;
+        let completion = match wait {
+            FingerWait::Capture => Completion::Captured(result),
+            FingerWait::FingerUp => Completion::FingerUp(result),
+        };
+        Ok(completion)
+    }
+
+    /// Complete the pending wait if IRQ is raised, same as `on_irq()`
+    pub fn poll(&mut self) -> nb::Result<Completion, Error<E>> {
+        self.on_irq()
+    }
+}
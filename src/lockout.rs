@@ -0,0 +1,332 @@
+//!
+//! ## Identify lockout
+//!
+//! `Lockout` wraps `BmLite::identify()` and locks out after a number of
+//! consecutive `Error::NoMatch` results. Each lockout in a row lasts twice
+//! as long as the one before, up to a configured maximum, and a match
+//! starts over from the first period.
+//!
+//! The counters are kept in a `LockoutStore` supplied by the application,
+//! typically flash or battery backed RAM, so a reset does not clear them.
+//! An attempt is stored as failed before the finger is identified, cutting
+//! power while the sensor works does not give a free try.
+//! Time is passed in by the caller in ms and must keep counting across
+//! resets, e.g. from an RTC.
+//!
+
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use {BmLite, Error, SensorSpi};
+
+/// Counters persisted between identifies
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LockoutState {
+    /// Consecutive failed identifies since the last match or lockout
+    pub failures: u32,
+    /// Lockouts in a row without a match in between
+    pub lockouts: u32,
+    /// Time the current lockout ends, ms
+    pub locked_until: u64,
+}
+
+/// Storage surviving resets for `LockoutState`
+pub trait LockoutStore {
+    /// State last stored, or the default state if nothing is stored
+    fn load(&mut self) -> LockoutState;
+    fn store(&mut self, state: &LockoutState);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LockoutPolicy {
+    /// Failed identifies that trigger a lockout
+    pub max_failures: u32,
+    /// Length of the first lockout, ms
+    pub lockout_ms: u64,
+    /// Upper limit for the doubled lockouts, ms
+    pub max_lockout_ms: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_failures: 5,
+            lockout_ms: 30_000,
+            max_lockout_ms: 3_600_000,
+        }
+    }
+}
+
+/// Identify with lockout, see module documentation
+pub struct Lockout<S> {
+    policy: LockoutPolicy,
+    store: S,
+    state: LockoutState,
+}
+
+impl<S: LockoutStore> Lockout<S> {
+    pub fn new(policy: LockoutPolicy, mut store: S) -> Self {
+        let state = store.load();
+        Lockout {
+            policy,
+            store,
+            state,
+        }
+    }
+
+    /// Time left of the current lockout at `now`, 0 if not locked out
+    pub fn remaining(&self, now: u64) -> u64 {
+        self.state.locked_until.saturating_sub(now)
+    }
+
+    pub fn state(&self) -> LockoutState {
+        self.state
+    }
+
+    /// Lift any lockout and clear the counters, e.g. after an
+    /// administrator has authenticated by other means
+    pub fn clear(&mut self) {
+        self.state = LockoutState::default();
+        self.store.store(&self.state);
+    }
+
+    /// Identify a finger unless locked out at `now`
+    pub fn identify<DEV, RST, IRQ, E>(
+        &mut self,
+        bm: &mut BmLite<DEV, RST, IRQ>,
+        now: u64,
+    ) -> Result<u32, Error<E>>
+    where
+        DEV: SensorSpi<Error = E>,
+        RST: OutputPin,
+        IRQ: InputPin,
+    {
+        // Attempt counted before a reset may have used up the last one
+        if self.remaining(now) == 0 && self.state.failures >= self.policy.max_failures {
+            self.lock_out(now);
+            self.store.store(&self.state);
+        }
+        let remaining = self.remaining(now);
+        if remaining > 0 {
+            return Err(Error::LockedOut { remaining });
+        }
+
+        self.state.failures += 1;
+        self.store.store(&self.state);
+        match bm.identify() {
+            Ok(id) => {
+                self.state = LockoutState::default();
+                self.store.store(&self.state);
+                Ok(id)
+            }
+            Err(Error::NoMatch) => {
+                if self.state.failures >= self.policy.max_failures {
+                    self.lock_out(now);
+                    self.store.store(&self.state);
+                }
+                Err(Error::NoMatch)
+            }
+            // Link and sensor errors say nothing about the finger
+            Err(e) => {
+                self.state.failures -= 1;
+                self.store.store(&self.state);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    /// Start the next lockout in a row at `now`
+    fn lock_out(&mut self, now: u64) {
+        let doubling = self.state.lockouts.min(32);
+        let period = self
+            .policy
+            .lockout_ms
+            .saturating_mul(1u64 << doubling)
+            .min(self.policy.max_lockout_ms);
+        self.state.failures = 0;
+        self.state.lockouts = self.state.lockouts.saturating_add(1);
+        self.state.locked_until = now.saturating_add(period);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate embedded_hal_mock;
+    extern crate std;
+    use self::embedded_hal_mock::spi::Transaction as SpiTransaction;
+    use self::std::vec::Vec;
+    use super::*;
+    use tests::{
+        answer, mock_done, mock_driver, mock_packages, package, package_transactions, MockBmLite,
+    };
+    use {TransportBuffer, ARG_ID, ARG_MATCH, CMD_CAPTURE};
+
+    // Keeps the state, the number of stores and the most failures stored
+    struct Ram(LockoutState, u32, u32);
+    impl LockoutStore for &mut Ram {
+        fn load(&mut self) -> LockoutState {
+            self.0
+        }
+        fn store(&mut self, state: &LockoutState) {
+            self.0 = *state;
+            self.1 += 1;
+            self.2 = self.2.max(state.failures);
+        }
+    }
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            max_failures: 3,
+            lockout_ms: 1000,
+            max_lockout_ms: 3000,
+        }
+    }
+
+    // Driver answering one identify per entry, matching template 1 if true
+    fn identifies(matches: &[bool]) -> MockBmLite {
+        let mut packages = Vec::new();
+        for &matched in matches.iter() {
+            packages.push((::capture_command(0), answer(CMD_CAPTURE, 0)));
+            packages.push((package(0x0005).add_arg(0x0008), answer(0x0005, 0)));
+            let identify = if matched {
+                answer(0x0003, 0)
+                    .add_arg_u8(ARG_MATCH, 1)
+                    .add_arg_u16(ARG_ID, 1)
+            } else {
+                answer(0x0003, 0).add_arg_u8(ARG_MATCH, 0)
+            };
+            packages.push((package(0x0003), identify));
+        }
+        let (expectations, irq) = mock_packages(&packages);
+        mock_driver(&expectations, &irq, &[])
+    }
+
+    fn no_match<E>(result: Result<u32, Error<E>>) {
+        match result {
+            Err(Error::NoMatch) => {}
+            _ => panic!("Expected no match"),
+        }
+    }
+
+    #[test]
+    fn lockout_doubles_until_match() {
+        let mut attempts = [false; 10];
+        attempts[9] = true;
+        let mut bm = identifies(&attempts);
+        let mut ram = Ram(LockoutState::default(), 0, 0);
+        {
+            let mut lockout = Lockout::new(policy(), &mut ram);
+            no_match(lockout.identify(&mut bm, 0));
+            no_match(lockout.identify(&mut bm, 0));
+            assert_eq!(lockout.remaining(0), 0);
+            no_match(lockout.identify(&mut bm, 10));
+            assert_eq!(lockout.remaining(10), 1000);
+            // Refused without asking the sensor
+            match lockout.identify(&mut bm, 600) {
+                Err(Error::LockedOut { remaining }) => assert_eq!(remaining, 410),
+                _ => panic!("Identify during lockout"),
+            }
+
+            for _ in 0..3 {
+                no_match(lockout.identify(&mut bm, 2000));
+            }
+            assert_eq!(lockout.remaining(2000), 2000);
+            for _ in 0..3 {
+                no_match(lockout.identify(&mut bm, 5000));
+            }
+            assert_eq!(lockout.remaining(5000), 3000);
+
+            match lockout.identify(&mut bm, 9000) {
+                Ok(id) => assert_eq!(id, 1),
+                _ => panic!("Match after lockout"),
+            }
+            assert_eq!(lockout.state(), LockoutState::default());
+        }
+        assert_eq!(ram.0, LockoutState::default());
+        assert_eq!(ram.2, 3);
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn lockout_survives_reset() {
+        // Power was cut after the third attempt was stored
+        let mut ram = Ram(
+            LockoutState {
+                failures: 3,
+                ..LockoutState::default()
+            },
+            0,
+            0,
+        );
+        let mut bm = identifies(&[]);
+        {
+            let mut lockout = Lockout::new(policy(), &mut ram);
+            match lockout.identify(&mut bm, 100) {
+                Err(Error::LockedOut { remaining }) => assert_eq!(remaining, 1000),
+                _ => panic!("Last attempt given again after reset"),
+            }
+        }
+        assert_eq!(ram.0.locked_until, 1100);
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn lockout_ends_at_clock_limit() {
+        let mut ram = Ram(
+            LockoutState {
+                failures: 3,
+                ..LockoutState::default()
+            },
+            0,
+            0,
+        );
+        let mut bm = identifies(&[]);
+        let now = u64::MAX - 10;
+        {
+            let mut lockout = Lockout::new(policy(), &mut ram);
+            match lockout.identify(&mut bm, now) {
+                Err(Error::LockedOut { remaining }) => assert_eq!(remaining, 10),
+                _ => panic!("Lockout wrapped around the clock"),
+            }
+        }
+        assert_eq!(ram.0.locked_until, u64::MAX);
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn lockout_takes_back_attempt_on_link_error() {
+        let (mut expectations, _) =
+            package_transactions(&::capture_command(0), &answer(CMD_CAPTURE, 0), 256);
+        expectations.truncate(1);
+        expectations.push(SpiTransaction::transfer(
+            [0, 0, 0, 0].to_vec(),
+            [0x7f, 0xff, 0x00, 0x7f].to_vec(),
+        ));
+        let mut bm = mock_driver(&expectations, &[false], &[]);
+        assert!(bm
+            .set_retry_policy(::RetryPolicy {
+                resend: 0,
+                ..::RetryPolicy::default()
+            })
+            .is_ok());
+        let mut ram = Ram(LockoutState::default(), 0, 0);
+        {
+            let mut lockout = Lockout::new(policy(), &mut ram);
+            match lockout.identify(&mut bm, 0) {
+                Err(Error::Nack) => {}
+                _ => panic!("Link error not reported"),
+            }
+        }
+        // Counted while the sensor worked, the finger was never judged
+        assert_eq!(ram.2, 1);
+        assert_eq!(ram.0, LockoutState::default());
+
+        mock_done(bm);
+    }
+}
@@ -0,0 +1,324 @@
+//!
+//! ## Host side user registry
+//!
+//! The sensor only stores numbered templates. `UserRegistry` keeps the
+//! users those templates belong to on the host, so `identify_user()` can
+//! answer with a person rather than a slot number. The `BmLite` methods in
+//! this module only touch the registry once the sensor has accepted the
+//! matching save or delete, keeping the two in step.
+//!
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use {BmLite, EnrollEvent, EnrollOptions, Error, SensorSpi};
+
+/// A user and the templates enrolled for them
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserRecord {
+    pub name: String,
+    /// External badge id, unique per user
+    pub badge: u32,
+    /// Application defined role bits
+    pub roles: u32,
+    /// Sensor template ids of the user's fingers
+    pub templates: Vec<u16>,
+}
+
+impl UserRecord {
+    /// New user without any enrolled fingers
+    pub fn new(name: String, badge: u32, roles: u32) -> Self {
+        UserRecord {
+            name,
+            badge,
+            roles,
+            templates: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistryError {
+    /// Another user already has this badge
+    BadgeInUse(u32),
+    /// No user with this badge
+    UnknownUser(u32),
+    /// Template id is not registered to any user
+    UnknownTemplate(u16),
+    /// Sensor answered delete all with this non-zero result, the
+    /// registry was left as it was
+    DeleteFailed(u8),
+}
+
+/// Users known to the host, see module documentation
+#[derive(Clone, Debug, Default)]
+pub struct UserRegistry {
+    users: Vec<UserRecord>,
+}
+
+impl UserRegistry {
+    pub fn new() -> Self {
+        UserRegistry { users: Vec::new() }
+    }
+
+    /// Add a user. Templates already listed in the record are taken as is,
+    /// normally they are added by `BmLite::enroll_user()`.
+    pub fn add_user(&mut self, user: UserRecord) -> Result<(), RegistryError> {
+        if self.user(user.badge).is_some() {
+            return Err(RegistryError::BadgeInUse(user.badge));
+        }
+        self.users.push(user);
+        Ok(())
+    }
+
+    pub fn users(&self) -> &[UserRecord] {
+        &self.users
+    }
+
+    pub fn user(&self, badge: u32) -> Option<&UserRecord> {
+        self.users.iter().find(|u| u.badge == badge)
+    }
+
+    /// User owning template `id`
+    pub fn user_for_template(&self, id: u16) -> Option<&UserRecord> {
+        self.users.iter().find(|u| u.templates.contains(&id))
+    }
+
+    fn attach(&mut self, badge: u32, id: u16) -> Result<(), RegistryError> {
+        match self.users.iter_mut().find(|u| u.badge == badge) {
+            Some(user) => {
+                user.templates.push(id);
+                Ok(())
+            }
+            None => Err(RegistryError::UnknownUser(badge)),
+        }
+    }
+
+    fn detach(&mut self, id: u16) {
+        for user in self.users.iter_mut() {
+            user.templates.retain(|&t| t != id);
+        }
+    }
+
+    fn remove(&mut self, badge: u32) -> Option<UserRecord> {
+        let pos = self.users.iter().position(|u| u.badge == badge)?;
+        Some(self.users.remove(pos))
+    }
+}
+
+impl<DEV, RST, IRQ, E> BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi<Error = E>,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    /// Enroll a finger for the user with `badge` and register the new
+    /// template to them. Returns the template id.
+    pub fn enroll_user<F>(
+        &mut self,
+        registry: &mut UserRegistry,
+        badge: u32,
+        options: EnrollOptions,
+        f: F,
+    ) -> Result<u16, Error<E>>
+    where
+        F: FnMut(EnrollEvent),
+    {
+        if registry.user(badge).is_none() {
+            return Err(Error::Registry(RegistryError::UnknownUser(badge)));
+        }
+        let id = self.enroll_id(options, f)?;
+        registry.attach(badge, id).map_err(Error::Registry)?;
+        Ok(id)
+    }
+
+    /// Identify a finger and return the user it is registered to
+    pub fn identify_user<'r>(
+        &mut self,
+        registry: &'r UserRegistry,
+    ) -> Result<&'r UserRecord, Error<E>> {
+        let id = u16::try_from(self.identify()?).map_err(|_| Error::UnexpectedResponse)?;
+        registry
+            .user_for_template(id)
+            .ok_or(Error::Registry(RegistryError::UnknownTemplate(id)))
+    }
+
+    /// Delete one template from the sensor and from its user
+    pub fn delete_user_template(
+        &mut self,
+        registry: &mut UserRegistry,
+        id: u16,
+    ) -> Result<(), Error<E>> {
+        self.delete_template(id)?;
+        registry.detach(id);
+        Ok(())
+    }
+
+    /// Delete every template of the user with `badge` and remove the user.
+    /// If the sensor fails part way the user is kept with the templates
+    /// that are still stored.
+    pub fn remove_user(
+        &mut self,
+        registry: &mut UserRegistry,
+        badge: u32,
+    ) -> Result<UserRecord, Error<E>> {
+        let templates = match registry.user(badge) {
+            Some(user) => user.templates.clone(),
+            None => return Err(Error::Registry(RegistryError::UnknownUser(badge))),
+        };
+        for id in templates {
+            self.delete_user_template(registry, id)?;
+        }
+        registry
+            .remove(badge)
+            .ok_or(Error::Registry(RegistryError::UnknownUser(badge)))
+    }
+
+    /// Delete all templates on the sensor and forget all users. The
+    /// registry is only cleared once the sensor reports success.
+    pub fn delete_all_users(&mut self, registry: &mut UserRegistry) -> Result<(), Error<E>> {
+        match self.delete_all()? {
+            0 => {
+                registry.users.clear();
+                Ok(())
+            }
+            result => Err(Error::Registry(RegistryError::DeleteFailed(result))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn templates_follow_their_user() {
+        let mut registry = UserRegistry::new();
+        let ok = registry.add_user(UserRecord::new("Ada".to_string(), 17, 0x1));
+        assert_eq!(ok, Ok(()));
+        let taken = registry.add_user(UserRecord::new("Bob".to_string(), 17, 0x0));
+        assert_eq!(taken, Err(RegistryError::BadgeInUse(17)));
+
+        assert_eq!(registry.attach(17, 3), Ok(()));
+        assert_eq!(registry.attach(17, 4), Ok(()));
+        assert_eq!(registry.attach(99, 5), Err(RegistryError::UnknownUser(99)));
+        assert_eq!(registry.user_for_template(4).map(|u| u.badge), Some(17));
+
+        registry.detach(3);
+        assert!(registry.user_for_template(3).is_none());
+        assert_eq!(registry.user(17).unwrap().templates, [4].to_vec());
+    }
+
+    #[test]
+    fn enroll_identify_and_remove_user() {
+        use tests::{answer, enroll_packages, mock_done, mock_driver, mock_packages, package};
+        use {TransportBuffer, ARG_ID, ARG_MATCH, CMD_CAPTURE};
+        // Ids 1 and 3 are in use
+        let mut packages = enroll_packages(&[1, 3]);
+        packages.extend_from_slice(&[
+            // Save as template 2
+            (
+                package(0x0006).add_arg(0x1008).add_arg_u16(ARG_ID, 2),
+                answer(0x0006, 0),
+            ),
+            (::capture_command(0), answer(CMD_CAPTURE, 0)),
+            // Extract
+            (package(0x0005).add_arg(0x0008), answer(0x0005, 0)),
+            // Identify matches template 2
+            (
+                package(0x0003),
+                answer(0x0003, 0)
+                    .add_arg_u8(ARG_MATCH, 1)
+                    .add_arg_u16(ARG_ID, 2),
+            ),
+            // Delete template 2
+            (
+                package(0x4002).add_arg(0x1009).add_arg_u16(ARG_ID, 2),
+                answer(0x4002, 0),
+            ),
+        ]);
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        let mut registry = UserRegistry::new();
+        let ok = registry.add_user(UserRecord::new("Ada".to_string(), 17, 0x1));
+        assert_eq!(ok, Ok(()));
+
+        match bm.enroll_user(&mut registry, 17, EnrollOptions::default(), |_| {}) {
+            Ok(id) => assert_eq!(id, 2),
+            Err(_) => panic!("Enroll failed"),
+        }
+        assert_eq!(registry.user(17).unwrap().templates, [2].to_vec());
+        match bm.identify_user(&registry) {
+            Ok(user) => assert_eq!(user.badge, 17),
+            Err(_) => panic!("Registered finger not identified"),
+        }
+        match bm.remove_user(&mut registry, 17) {
+            Ok(user) => assert_eq!(user.badge, 17),
+            Err(_) => panic!("User not removed"),
+        }
+        assert!(registry.users().is_empty());
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn failed_delete_all_keeps_users() {
+        use tests::{answer, mock_done, mock_driver, mock_packages, package};
+        use TransportBuffer;
+        let delete_all = package(0x4002).add_arg(0x1009).add_arg(0x0007);
+        let packages = [
+            (delete_all.clone(), answer(0x4002, 5)),
+            (delete_all, answer(0x4002, 0)),
+        ];
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        let mut registry = UserRegistry::new();
+        let mut ada = UserRecord::new("Ada".to_string(), 17, 0x1);
+        ada.templates.push(1);
+        assert_eq!(registry.add_user(ada), Ok(()));
+
+        match bm.delete_all_users(&mut registry) {
+            Err(Error::Registry(RegistryError::DeleteFailed(5))) => {}
+            _ => panic!("Failed delete all was not reported"),
+        }
+        assert_eq!(registry.users().len(), 1);
+        assert!(bm.delete_all_users(&mut registry).is_ok());
+        assert!(registry.users().is_empty());
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn identify_user_refuses_wide_ids() {
+        use tests::{answer, mock_done, mock_driver, mock_packages, package};
+        use {TransportBuffer, ARG_ID, ARG_MATCH, CMD_CAPTURE};
+        let packages = [
+            (::capture_command(0), answer(CMD_CAPTURE, 0)),
+            (package(0x0005).add_arg(0x0008), answer(0x0005, 0)),
+            // Matches an id a template id cannot hold
+            (
+                package(0x0003),
+                answer(0x0003, 0)
+                    .add_arg_u8(ARG_MATCH, 1)
+                    .add_arg_u32(ARG_ID, 0x1_0002),
+            ),
+        ];
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        let mut registry = UserRegistry::new();
+        let mut ada = UserRecord::new("Ada".to_string(), 17, 0x1);
+        ada.templates.push(2);
+        assert_eq!(registry.add_user(ada), Ok(()));
+
+        match bm.identify_user(&registry) {
+            Err(Error::UnexpectedResponse) => {}
+            _ => panic!("Match id was truncated"),
+        }
+
+        mock_done(bm);
+    }
+}
@@ -0,0 +1,241 @@
+//!
+//! ## Sealed template export
+//!
+//! Templates are biometric data and must not leave the sensor in the clear.
+//! `SealedTemplate` wraps one template in ChaCha20-Poly1305 with a caller
+//! provided 256 bit key. The template id and the firmware version it was
+//! read from are authenticated as associated data, so a blob that has been
+//! altered in any way, or moved to another id, is refused on import. So is
+//! a blob read from other firmware than the sensor runs.
+//!
+//! Blob layout, all fields little endian:
+//! magic "BMLS", version u16, id u16, firmware length u16, firmware
+//! version, 12 byte nonce, ciphertext with 16 byte tag.
+//! Everything before the nonce is the associated data.
+//!
+
+use alloc::vec::Vec;
+
+use byteorder::{ByteOrder, LittleEndian};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use {AuditEvent, BmLite, Error, SensorSpi, TransportBuffer};
+
+/// Blob format written by `SealedTemplate::to_bytes()`
+pub const SEALED_VERSION: u16 = 1;
+const SEALED_MAGIC: [u8; 4] = [b'B', b'M', b'L', b'S'];
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SealError {
+    /// Data is not a sealed template blob
+    Malformed,
+    /// Blob version this driver cannot read
+    Version(u16),
+    /// Wrong key, or blob content altered after sealing
+    Tampered,
+    /// Template was read from other firmware than the sensor runs
+    Firmware,
+}
+
+/// One encrypted template, see module documentation
+#[derive(Clone, Debug, PartialEq)]
+pub struct SealedTemplate {
+    pub id: u16,
+    /// Version string of the firmware the template was read from
+    pub firmware: Vec<u8>,
+    pub nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+impl SealedTemplate {
+    /// Encrypt `template` under `key`. The nonce must never be used twice
+    /// with the same key, a counter kept by the caller is enough.
+    pub fn seal(
+        key: &[u8; 32],
+        nonce: [u8; 12],
+        id: u16,
+        firmware: &[u8],
+        template: &[u8],
+    ) -> Result<SealedTemplate, SealError> {
+        let aad = associated_data(id, firmware);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: template,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| SealError::Malformed)?;
+        Ok(SealedTemplate {
+            id,
+            firmware: firmware.to_vec(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt and authenticate the template
+    pub fn open(&self, key: &[u8; 32]) -> Result<Vec<u8>, SealError> {
+        let aad = associated_data(self.id, &self.firmware);
+        ChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(
+                Nonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| SealError::Tampered)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = associated_data(self.id, &self.firmware);
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&self.ciphertext);
+        out
+    }
+
+    /// Parse a blob. Only the layout is checked here, `open()` tells if
+    /// the content is authentic.
+    pub fn from_bytes(data: &[u8]) -> Result<SealedTemplate, SealError> {
+        if data.len() < 10 || data[0..4] != SEALED_MAGIC {
+            return Err(SealError::Malformed);
+        }
+        let version = LittleEndian::read_u16(&data[4..6]);
+        if version != SEALED_VERSION {
+            return Err(SealError::Version(version));
+        }
+        let id = LittleEndian::read_u16(&data[6..8]);
+        let len = LittleEndian::read_u16(&data[8..10]) as usize;
+        let nonce_at = 10 + len;
+        if data.len() < nonce_at + NONCE_LEN + TAG_LEN {
+            return Err(SealError::Malformed);
+        }
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&data[nonce_at..nonce_at + NONCE_LEN]);
+        Ok(SealedTemplate {
+            id,
+            firmware: data[10..nonce_at].to_vec(),
+            nonce,
+            ciphertext: data[nonce_at + NONCE_LEN..].to_vec(),
+        })
+    }
+}
+
+fn associated_data(id: u16, firmware: &[u8]) -> Vec<u8> {
+    let mut aad = SEALED_MAGIC
+        .to_vec()
+        .push_u16(SEALED_VERSION)
+        .push_u16(id)
+        .push_u16(firmware.len() as u16);
+    aad.extend_from_slice(firmware);
+    aad
+}
+
+impl<DEV, RST, IRQ, E> BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi<Error = E>,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    /// Read template `id` from storage and seal it under `key`
+    pub fn export_template(
+        &mut self,
+        id: u16,
+        key: &[u8; 32],
+        nonce: [u8; 12],
+    ) -> Result<SealedTemplate, Error<E>> {
+        let firmware = self.get_version()?.to_vec();
+        self.load_template(id)?;
+        let template = self.read_template()?;
+        SealedTemplate::seal(key, nonce, id, &firmware, &template).map_err(Error::Sealed)
+    }
+
+    /// Open a sealed template and save it in storage under its own id.
+    /// Nothing is written to the sensor unless the blob is authentic, and
+    /// a template read from other firmware is refused with
+    /// `SealError::Firmware`.
+    pub fn import_template(
+        &mut self,
+        sealed: &SealedTemplate,
+        key: &[u8; 32],
+    ) -> Result<(), Error<E>> {
+        let template = sealed.open(key).map_err(Error::Sealed)?;
+        if self.get_version()? != &sealed.firmware[..] {
+            return Err(Error::Sealed(SealError::Firmware));
+        }
+        self.write_template(&template)?;
+        self.do_savetemplate(sealed.id)?;
+        self.audit(AuditEvent::Imported { id: sealed.id });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    fn sealed() -> SealedTemplate {
+        SealedTemplate::seal(&KEY, [7; 12], 3, b"2.1", &[0x10, 0x20, 0x30, 0x40]).unwrap()
+    }
+
+    #[test]
+    fn sealed_round_trip() {
+        let blob = sealed().to_bytes();
+        let parsed = SealedTemplate::from_bytes(&blob).unwrap();
+        assert_eq!(parsed, sealed());
+        assert_eq!(parsed.open(&KEY), Ok([0x10, 0x20, 0x30, 0x40].to_vec()));
+        assert_eq!(parsed.open(&[0x43; 32]), Err(SealError::Tampered));
+    }
+
+    #[test]
+    fn sealed_refuses_tampering() {
+        let blob = sealed().to_bytes();
+        // Every byte is either associated data, nonce or ciphertext
+        for i in 0..blob.len() {
+            let mut bad = blob.clone();
+            bad[i] ^= 0x01;
+            let opened = SealedTemplate::from_bytes(&bad).and_then(|s| s.open(&KEY));
+            assert!(opened.is_err(), "byte {} not authenticated", i);
+        }
+    }
+
+    #[test]
+    fn import_refuses_other_firmware() {
+        use tests::{answer, mock_done, mock_driver, mock_packages, package, version_query};
+        use {ARG_DATA, ARG_DOWNLOAD, ARG_ID};
+        let packages = [
+            version_query(b"2.2"),
+            version_query(b"2.1"),
+            (
+                package(0x0006)
+                    .add_arg(ARG_DOWNLOAD)
+                    .add_arg_data(ARG_DATA, &[0x10, 0x20, 0x30, 0x40]),
+                answer(0x0006, 0),
+            ),
+            // Saved under the sealed id
+            (
+                package(0x0006).add_arg(0x1008).add_arg_u16(ARG_ID, 3),
+                answer(0x0006, 0),
+            ),
+        ];
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+
+        match bm.import_template(&sealed(), &KEY) {
+            Err(Error::Sealed(SealError::Firmware)) => {}
+            _ => panic!("Template from other firmware imported"),
+        }
+        assert!(bm.import_template(&sealed(), &KEY).is_ok());
+
+        mock_done(bm);
+    }
+}
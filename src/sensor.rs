@@ -0,0 +1,271 @@
+//!
+//! ## Sensor abstraction
+//!
+//! `FingerprintSensor` is the part of the driver application code needs,
+//! so it can be written once and run against `BmLite`, other sensor
+//! models or `FakeSensor` in unit tests.
+//!
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use embedded_hal::digital::{InputPin, OutputPin};
+
+#[cfg(feature = "alloc")]
+use lowest_free_id;
+use {BmLite, EnrollOptions, Error, SensorSpi};
+
+pub trait FingerprintSensor {
+    type Error;
+
+    /// Capture an image, false if no finger was found in time.
+    /// Timeout in ms but 0 waits forever
+    fn capture(&mut self, timeout: u32) -> Result<bool, Self::Error>;
+
+    /// Enroll a finger and store it, returns the template id
+    fn enroll(&mut self) -> Result<u16, Self::Error>;
+
+    /// Template id of the finger on the sensor, None if it is not stored
+    fn identify(&mut self) -> Result<Option<u16>, Self::Error>;
+
+    /// Check if the finger on the sensor is template `id`
+    fn verify(&mut self, id: u16) -> Result<bool, Self::Error> {
+        Ok(self.identify()? == Some(id))
+    }
+
+    /// Delete template `id` from storage
+    fn delete(&mut self, id: u16) -> Result<(), Self::Error>;
+
+    /// Number of stored templates
+    fn count(&mut self) -> Result<u32, Self::Error>;
+
+    /// Firmware version string, borrowed until the next call
+    fn version(&mut self) -> Result<&[u8], Self::Error>;
+}
+
+impl<DEV, RST, IRQ, E> FingerprintSensor for BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi<Error = E>,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    type Error = Error<E>;
+
+    fn capture(&mut self, timeout: u32) -> Result<bool, Error<E>> {
+        BmLite::capture(self, timeout).map(|result| result == 0)
+    }
+
+    fn enroll(&mut self) -> Result<u16, Error<E>> {
+        self.enroll_id(EnrollOptions::default(), |_| {})
+    }
+
+    fn identify(&mut self) -> Result<Option<u16>, Error<E>> {
+        match BmLite::identify(self) {
+            Ok(id) => u16::try_from(id)
+                .map(Some)
+                .map_err(|_| Error::UnexpectedResponse),
+            Err(Error::NoMatch) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn delete(&mut self, id: u16) -> Result<(), Error<E>> {
+        self.delete_template(id)
+    }
+
+    fn count(&mut self) -> Result<u32, Error<E>> {
+        self.get_template_count()
+    }
+
+    fn version(&mut self) -> Result<&[u8], Error<E>> {
+        self.get_version()
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FakeError {
+    /// Operation needs a finger on the sensor
+    NoFinger,
+    /// Template storage is full
+    Full,
+    UnknownTemplate(u16),
+}
+
+/// In-memory sensor for tests. Fingers are plain numbers, the same number
+/// placed again is the same finger.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug)]
+pub struct FakeSensor {
+    version: Vec<u8>,
+    capacity: usize,
+    finger: Option<u32>,
+    // (template id, finger)
+    templates: Vec<(u16, u32)>,
+}
+
+#[cfg(feature = "alloc")]
+impl FakeSensor {
+    pub fn new(capacity: usize) -> Self {
+        FakeSensor {
+            version: [b'f', b'a', b'k', b'e'].to_vec(),
+            capacity,
+            finger: None,
+            templates: Vec::new(),
+        }
+    }
+
+    pub fn place_finger(&mut self, finger: u32) {
+        self.finger = Some(finger);
+    }
+
+    pub fn lift_finger(&mut self) {
+        self.finger = None;
+    }
+
+    fn finger(&self) -> Result<u32, FakeError> {
+        self.finger.ok_or(FakeError::NoFinger)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl FingerprintSensor for FakeSensor {
+    type Error = FakeError;
+
+    fn capture(&mut self, _timeout: u32) -> Result<bool, FakeError> {
+        Ok(self.finger.is_some())
+    }
+
+    fn enroll(&mut self) -> Result<u16, FakeError> {
+        let finger = self.finger()?;
+        if self.templates.len() >= self.capacity {
+            return Err(FakeError::Full);
+        }
+        // Same allocation as the sensor
+        let id = lowest_free_id(self.templates.iter().map(|&(t, _)| t)).ok_or(FakeError::Full)?;
+        self.templates.push((id, finger));
+        Ok(id)
+    }
+
+    fn identify(&mut self) -> Result<Option<u16>, FakeError> {
+        let finger = self.finger()?;
+        Ok(self
+            .templates
+            .iter()
+            .find(|&&(_, f)| f == finger)
+            .map(|&(id, _)| id))
+    }
+
+    fn delete(&mut self, id: u16) -> Result<(), FakeError> {
+        match self.templates.iter().position(|&(t, _)| t == id) {
+            Some(pos) => {
+                self.templates.remove(pos);
+                Ok(())
+            }
+            None => Err(FakeError::UnknownTemplate(id)),
+        }
+    }
+
+    fn count(&mut self) -> Result<u32, FakeError> {
+        Ok(self.templates.len() as u32)
+    }
+
+    fn version(&mut self) -> Result<&[u8], FakeError> {
+        Ok(&self.version)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+
+    // Written against the trait only, like application code
+    fn enroll_new<S: FingerprintSensor>(sensor: &mut S) -> Result<Option<u16>, S::Error> {
+        match sensor.identify()? {
+            Some(_) => Ok(None),
+            None => sensor.enroll().map(Some),
+        }
+    }
+
+    #[test]
+    fn fake_sensor_behaves_like_storage() {
+        let mut sensor = FakeSensor::new(2);
+        assert_eq!(sensor.capture(0), Ok(false));
+        assert_eq!(enroll_new(&mut sensor), Err(FakeError::NoFinger));
+
+        sensor.place_finger(11);
+        assert_eq!(enroll_new(&mut sensor), Ok(Some(1)));
+        assert_eq!(enroll_new(&mut sensor), Ok(None));
+        sensor.place_finger(22);
+        assert_eq!(enroll_new(&mut sensor), Ok(Some(2)));
+        sensor.place_finger(33);
+        assert_eq!(enroll_new(&mut sensor), Err(FakeError::Full));
+
+        sensor.place_finger(22);
+        assert_eq!(sensor.verify(2), Ok(true));
+        assert_eq!(sensor.verify(1), Ok(false));
+        assert_eq!(sensor.delete(1), Ok(()));
+        assert_eq!(sensor.delete(1), Err(FakeError::UnknownTemplate(1)));
+        assert_eq!(sensor.count(), Ok(1));
+
+        sensor.place_finger(33);
+        assert_eq!(enroll_new(&mut sensor), Ok(Some(1)));
+    }
+
+    #[test]
+    fn fake_sensor_allocates_like_bmlite() {
+        use tests::{answer, enroll_packages, mock_done, mock_driver, mock_packages, package};
+        use {TransportBuffer, ARG_ID};
+        // Ids 1 and 3 are in use
+        let mut packages = enroll_packages(&[1, 3]);
+        // Save as template 2
+        packages.push((
+            package(0x0006).add_arg(0x1008).add_arg_u16(ARG_ID, 2),
+            answer(0x0006, 0),
+        ));
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+
+        let mut fake = FakeSensor::new(4);
+        for finger in 1..4 {
+            fake.place_finger(finger);
+            assert!(fake.enroll().is_ok());
+        }
+        assert_eq!(fake.delete(2), Ok(()));
+        fake.place_finger(4);
+
+        match FingerprintSensor::enroll(&mut bm) {
+            Ok(id) => assert_eq!(Ok(id), fake.enroll()),
+            _ => panic!("Enroll failed"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn bmlite_refuses_wide_match_id() {
+        use tests::{answer, mock_done, mock_driver, mock_packages, package};
+        use {TransportBuffer, ARG_ID, ARG_MATCH, CMD_CAPTURE};
+        let packages = [
+            (::capture_command(0), answer(CMD_CAPTURE, 0)),
+            (package(0x0005).add_arg(0x0008), answer(0x0005, 0)),
+            // Matches an id a template id cannot hold
+            (
+                package(0x0003),
+                answer(0x0003, 0)
+                    .add_arg_u8(ARG_MATCH, 1)
+                    .add_arg_u32(ARG_ID, 0x1_0002),
+            ),
+        ];
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+
+        match FingerprintSensor::identify(&mut bm) {
+            Err(Error::UnexpectedResponse) => {}
+            _ => panic!("Match id was truncated"),
+        }
+
+        mock_done(bm);
+    }
+}
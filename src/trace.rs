@@ -0,0 +1,192 @@
+//!
+//! ## Frame tracing
+//!
+//! With the `trace` feature every frame the link sends or reads is passed
+//! as a `TraceEvent` to the hook set with `BmLite::set_trace_hook()`. The
+//! `log` and `defmt` features also emit each event at trace level through
+//! those crates. Without any of them tracing compiles to nothing.
+//!
+//! Events hold the raw bytes as on the bus, `cmd()` and `args()` decode
+//! the application package of the first frame of a command or response.
+//! `Display` and `defmt::Format` show both.
+//!
+
+use core::fmt;
+
+#[cfg(feature = "defmt")]
+use defmt;
+#[cfg(feature = "log")]
+use log;
+
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use {BmLite, SensorSpi};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEvent<'a> {
+    /// Transport frame sent, from link header to CRC
+    Command(&'a [u8]),
+    /// ACK, NACK or garbage read back for a command frame
+    CommandAck([u8; 4]),
+    /// Link header read ahead of a response, channel and link size
+    Header([u8; 4]),
+    /// Response transport frame read, CRC included
+    Response(&'a [u8]),
+    /// ACK or NACK sent for a response frame
+    ResponseAck([u8; 4]),
+}
+
+impl<'a> TraceEvent<'a> {
+    /// Bytes as clocked over the bus
+    pub fn raw(&self) -> &[u8] {
+        match *self {
+            TraceEvent::Command(frame) | TraceEvent::Response(frame) => frame,
+            TraceEvent::CommandAck(ref ack)
+            | TraceEvent::Header(ref ack)
+            | TraceEvent::ResponseAck(ref ack) => ack,
+        }
+    }
+
+    /// Application package in the frame, if it starts one
+    fn app(&self) -> Option<&'a [u8]> {
+        // Link header only on command frames, then size, seq nr, seq len
+        let (frame, start) = match *self {
+            TraceEvent::Command(frame) => (frame, 4),
+            TraceEvent::Response(frame) => (frame, 0),
+            _ => return None,
+        };
+        if frame.len() < start + 6 + 4 + 4 || frame[start + 2] != 1 || frame[start + 3] != 0 {
+            return None;
+        }
+        Some(&frame[start + 6..frame.len() - 4])
+    }
+
+    /// Command of the package, for the first frame of a command or response
+    pub fn cmd(&self) -> Option<u16> {
+        self.app()
+            .map(|app| u16::from(app[0]) | u16::from(app[1]) << 8)
+    }
+
+    /// Arguments of the package as far as they are in this frame
+    pub fn args(&self) -> Args<'a> {
+        Args(self.app().map(|app| &app[4..]).unwrap_or(&[]))
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            TraceEvent::Command(_) => "tx",
+            TraceEvent::CommandAck(_) => "tx ack",
+            TraceEvent::Header(_) => "rx header",
+            TraceEvent::Response(_) => "rx",
+            TraceEvent::ResponseAck(_) => "rx ack",
+        }
+    }
+}
+
+/// Iterator over `(id, data)` of package arguments
+#[derive(Clone, Debug)]
+pub struct Args<'a>(&'a [u8]);
+
+impl<'a> Iterator for Args<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u16, &'a [u8])> {
+        let args = self.0;
+        if args.len() < 4 {
+            return None;
+        }
+        let id = u16::from(args[0]) | u16::from(args[1]) << 8;
+        let len = u16::from(args[2]) | u16::from(args[3]) << 8;
+        let end = (4 + len as usize).min(args.len());
+        self.0 = &args[end..];
+        Some((id, &args[4..end]))
+    }
+}
+
+impl<'a> fmt::Display for TraceEvent<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(cmd) = self.cmd() {
+            write!(f, " cmd {:#06x}", cmd)?;
+            for (id, data) in self.args() {
+                write!(f, " {:#06x}:{:02x?}", id, data)?;
+            }
+        }
+        write!(f, " raw {:02x?}", self.raw())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a> defmt::Format for TraceEvent<'a> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}", self.name());
+        if let Some(cmd) = self.cmd() {
+            defmt::write!(f, " cmd {=u16:#06x}", cmd);
+            for (id, data) in self.args() {
+                defmt::write!(f, " {=u16:#06x}:{=[u8]:02x}", id, data);
+            }
+        }
+        defmt::write!(f, " raw {=[u8]:02x}", self.raw());
+    }
+}
+
+impl<DEV, RST, IRQ> BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    /// Pass every frame to `hook`, replaces any hook set before
+    #[cfg(feature = "trace")]
+    pub fn set_trace_hook(&mut self, hook: fn(&TraceEvent)) {
+        self.trace = Some(hook);
+    }
+
+    #[cfg(feature = "trace")]
+    pub fn clear_trace_hook(&mut self) {
+        self.trace = None;
+    }
+
+    #[cfg(feature = "trace")]
+    pub(crate) fn trace(&self, event: TraceEvent) {
+        #[cfg(feature = "log")]
+        log::trace!("{}", event);
+        #[cfg(feature = "defmt")]
+        defmt::trace!("{}", event);
+        if let Some(hook) = self.trace {
+            hook(&event);
+        }
+    }
+
+    #[cfg(not(feature = "trace"))]
+    #[inline(always)]
+    pub(crate) fn trace(&self, _event: TraceEvent) {}
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use self::std::string::ToString;
+    use self::std::vec::Vec;
+    use super::*;
+
+    #[test]
+    fn trace_decodes_command() {
+        // Capture with a 1000 ms timeout
+        let frame = [
+            0x01, 0x00, 0x12, 0x00, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00,
+            0x01, 0x50, 0x04, 0x00, 0xe8, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let event = TraceEvent::Command(&frame);
+        assert_eq!(event.cmd(), Some(0x0001));
+        let args: Vec<(u16, &[u8])> = event.args().collect();
+        assert_eq!(args, [(0x5001, &[0xe8, 0x03, 0x00, 0x00][..])].to_vec());
+        assert!(event
+            .to_string()
+            .starts_with("tx cmd 0x0001 0x5001:[e8, 03, 00, 00] raw [01, 00,"));
+
+        let ack = TraceEvent::CommandAck([0x7f, 0xff, 0x01, 0x7f]);
+        assert_eq!(ack.cmd(), None);
+        assert_eq!(ack.to_string(), "tx ack raw [7f, ff, 01, 7f]");
+    }
+}
@@ -0,0 +1,475 @@
+//!
+//! ## Template vault
+//!
+//! Backup of every template in sensor storage together with its id, used
+//! to move a sensor database to a replacement module. A `Vault` serializes
+//! to a single versioned archive protected by a CRC32. Templates never
+//! leave the `Vault` in the clear, each is stored as a `SealedTemplate`
+//! under a caller provided key.
+//!
+//! Archive layout, all fields little endian:
+//! magic "BMLV", version u16, firmware length u16, firmware version,
+//! entry count u16, per entry length u32 and sealed template blob,
+//! CRC32 of everything before it.
+//!
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use sealed::{SealError, SealedTemplate};
+use {AuditEvent, BmLite, Error, SensorSpi, TransportBuffer};
+
+/// Archive format written by `Vault::to_bytes()`, version 1 held the
+/// templates in the clear and is no longer read
+pub const VAULT_VERSION: u16 = 2;
+const VAULT_MAGIC: [u8; 4] = [b'B', b'M', b'L', b'V'];
+
+/// One stored template
+#[derive(Clone, Debug, PartialEq)]
+pub struct VaultEntry {
+    pub id: u16,
+    pub template: Vec<u8>,
+}
+
+/// All templates read from a sensor
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Vault {
+    /// Version string of the firmware the templates were read from
+    pub firmware: Vec<u8>,
+    pub entries: Vec<VaultEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VaultError {
+    /// Data is not a vault archive
+    BadMagic,
+    /// Archive version this driver cannot read
+    Version(u16),
+    /// Archive ends before its content does
+    Truncated,
+    /// Archive content does not match its checksum
+    Checksum,
+    /// Field too long for its length in the archive format
+    TooLarge,
+    /// Vault was read from other firmware than the sensor runs
+    Firmware,
+    /// Template could not be sealed, or its blob is malformed or fails
+    /// authentication under the given key
+    Sealed(SealError),
+}
+
+/// Difference between a vault and what is stored on a sensor
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VaultDiff {
+    /// In the vault but not stored on the sensor
+    pub missing: Vec<u16>,
+    /// Stored under the same id but with other content
+    pub changed: Vec<u16>,
+    /// Stored on the sensor but not in the vault, restore leaves these
+    pub extra: Vec<u16>,
+}
+
+impl Vault {
+    /// Serialize with every template sealed under `key`. `nonce` is called
+    /// once per template and must never return the same value twice for
+    /// one key, a counter kept by the caller is enough.
+    pub fn to_bytes<F>(&self, key: &[u8; 32], mut nonce: F) -> Result<Vec<u8>, VaultError>
+    where
+        F: FnMut() -> [u8; 12],
+    {
+        let mut out = VAULT_MAGIC
+            .to_vec()
+            .push_u16(VAULT_VERSION)
+            .push_u16(u16_len(self.firmware.len())?);
+        out.extend_from_slice(&self.firmware);
+        out = out.push_u16(u16_len(self.entries.len())?);
+        for entry in self.entries.iter() {
+            let blob =
+                SealedTemplate::seal(key, nonce(), entry.id, &self.firmware, &entry.template)
+                    .map_err(VaultError::Sealed)?
+                    .to_bytes();
+            let len = u32::try_from(blob.len()).map_err(|_| VaultError::TooLarge)?;
+            out = out.push_u32(len);
+            out.extend_from_slice(&blob);
+        }
+        let crc = crc32::checksum_ieee(&out);
+        Ok(out.push_u32(crc))
+    }
+
+    /// Parse an archive and open every template with `key`
+    pub fn from_bytes(data: &[u8], key: &[u8; 32]) -> Result<Vault, VaultError> {
+        if data.len() < 4 || data[0..4] != VAULT_MAGIC {
+            return Err(VaultError::BadMagic);
+        }
+        if data.len() < 4 + 2 + 4 {
+            return Err(VaultError::Truncated);
+        }
+        let version = LittleEndian::read_u16(&data[4..6]);
+        if version != VAULT_VERSION {
+            return Err(VaultError::Version(version));
+        }
+        let (body, crc) = data.split_at(data.len() - 4);
+        if crc32::checksum_ieee(body) != LittleEndian::read_u32(crc) {
+            return Err(VaultError::Checksum);
+        }
+
+        let mut pos = 6;
+        let len = LittleEndian::read_u16(take(body, &mut pos, 2)?) as usize;
+        let firmware = take(body, &mut pos, len)?.to_vec();
+        let count = LittleEndian::read_u16(take(body, &mut pos, 2)?);
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = LittleEndian::read_u32(take(body, &mut pos, 4)?) as usize;
+            let sealed = SealedTemplate::from_bytes(take(body, &mut pos, len)?)
+                .map_err(VaultError::Sealed)?;
+            // Archive header is not authenticated, the blob is
+            if sealed.firmware != firmware {
+                return Err(VaultError::Sealed(SealError::Tampered));
+            }
+            entries.push(VaultEntry {
+                id: sealed.id,
+                template: sealed.open(key).map_err(VaultError::Sealed)?,
+            });
+        }
+        Ok(Vault { firmware, entries })
+    }
+}
+
+fn u16_len(len: usize) -> Result<u16, VaultError> {
+    u16::try_from(len).map_err(|_| VaultError::TooLarge)
+}
+
+/// Next `len` bytes of `data` from `pos`, `len` is read from the archive
+/// and cannot be trusted
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], VaultError> {
+    let end = pos
+        .checked_add(len)
+        .filter(|&end| end <= data.len())
+        .ok_or(VaultError::Truncated)?;
+    let field = &data[*pos..end];
+    *pos = end;
+    Ok(field)
+}
+
+impl<DEV, RST, IRQ, E> BmLite<DEV, RST, IRQ>
+where
+    DEV: SensorSpi<Error = E>,
+    RST: OutputPin,
+    IRQ: InputPin,
+{
+    /// Read every template in sensor storage
+    pub fn backup(&mut self) -> Result<Vault, Error<E>> {
+        let firmware = self.get_version()?.to_vec();
+        let mut entries = Vec::new();
+        for id in self.template_ids()? {
+            self.load_template(id)?;
+            entries.push(VaultEntry {
+                id,
+                template: self.read_template()?,
+            });
+        }
+        Ok(Vault { firmware, entries })
+    }
+
+    /// Dry run of `restore()`, compare the vault with sensor storage
+    pub fn diff(&mut self, vault: &Vault) -> Result<VaultDiff, Error<E>> {
+        let stored = self.template_ids()?;
+        let mut diff = VaultDiff::default();
+        for entry in vault.entries.iter() {
+            if !stored.contains(&entry.id) {
+                diff.missing.push(entry.id);
+                continue;
+            }
+            self.load_template(entry.id)?;
+            if self.read_template()? != entry.template {
+                diff.changed.push(entry.id);
+            }
+        }
+        for &id in stored.iter() {
+            if !vault.entries.iter().any(|e| e.id == id) {
+                diff.extra.push(id);
+            }
+        }
+        Ok(diff)
+    }
+
+    /// Write the vault templates that are missing or changed on the sensor.
+    /// Returns what was found before restoring. Templates are only valid
+    /// for the firmware that made them, a vault from other firmware is
+    /// refused with `VaultError::Firmware`.
+    ///
+    /// Templates are restored in vault order. A changed template is only
+    /// deleted once its replacement is in sensor RAM. On error the
+    /// templates before the failing one are restored and the rest are as
+    /// they were, except that a changed template whose save failed is left
+    /// deleted. Run `diff()` to see where the sensor stands, `restore()`
+    /// again picks up from there.
+    pub fn restore(&mut self, vault: &Vault) -> Result<VaultDiff, Error<E>> {
+        if self.get_version()? != &vault.firmware[..] {
+            return Err(Error::Vault(VaultError::Firmware));
+        }
+        let diff = self.diff(vault)?;
+        for entry in vault.entries.iter() {
+            let changed = diff.changed.contains(&entry.id);
+            if !changed && !diff.missing.contains(&entry.id) {
+                continue;
+            }
+            self.write_template(&entry.template)?;
+            if changed {
+                self.delete_template(entry.id)?;
+            }
+            self.do_savetemplate(entry.id)?;
+            self.audit(AuditEvent::Imported { id: entry.id });
+        }
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{
+        answer, mock_done, mock_driver, mock_packages, package, template_ids, version_query,
+    };
+    use {Bytes, ARG_DATA, ARG_DOWNLOAD, ARG_ID, ARG_UPLOAD};
+
+    fn vault() -> Vault {
+        Vault {
+            firmware: [b'1', b'.', b'2'].to_vec(),
+            entries: [
+                VaultEntry {
+                    id: 1,
+                    template: [0x10, 0x20, 0x30].to_vec(),
+                },
+                VaultEntry {
+                    id: 7,
+                    template: [0x77; 300].to_vec(),
+                },
+            ]
+            .to_vec(),
+        }
+    }
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    fn archive() -> Vec<u8> {
+        let mut counter = 0u8;
+        vault()
+            .to_bytes(&KEY, || {
+                counter += 1;
+                [counter; 12]
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn archive_round_trip() {
+        let archive = archive();
+        assert_eq!(Vault::from_bytes(&archive, &KEY), Ok(vault()));
+        assert_eq!(
+            Vault::from_bytes(&archive, &[0x43; 32]),
+            Err(VaultError::Sealed(SealError::Tampered))
+        );
+    }
+
+    #[test]
+    fn archive_holds_no_plaintext() {
+        let archive = archive();
+        assert!(!archive.windows(3).any(|w| w == [0x10, 0x20, 0x30]));
+        assert!(!archive.windows(16).any(|w| w == [0x77; 16]));
+    }
+
+    #[test]
+    fn archive_rejects_tampering() {
+        let mut archive = archive();
+        archive[20] ^= 0x01;
+        assert_eq!(Vault::from_bytes(&archive, &KEY), Err(VaultError::Checksum));
+        archive[0] = b'X';
+        assert_eq!(Vault::from_bytes(&archive, &KEY), Err(VaultError::BadMagic));
+        let archive = self::archive();
+        assert_eq!(
+            Vault::from_bytes(&archive[..archive.len() - 1], &KEY),
+            Err(VaultError::Checksum)
+        );
+    }
+
+    #[test]
+    fn archive_refuses_oversized_entry() {
+        let mut archive = VAULT_MAGIC
+            .to_vec()
+            .push_u16(VAULT_VERSION)
+            .push_u16(0)
+            .push_u16(1)
+            .push_u32(u32::MAX);
+        archive.extend_from_slice(&[0; 16]);
+        let crc = crc32::checksum_ieee(&archive);
+        let archive = archive.push_u32(crc);
+        assert_eq!(
+            Vault::from_bytes(&archive, &KEY),
+            Err(VaultError::Truncated)
+        );
+    }
+
+    #[test]
+    fn archive_refuses_oversized_firmware() {
+        let mut vault = vault();
+        vault.firmware = [b'x'; 0x10000].to_vec();
+        assert_eq!(vault.to_bytes(&KEY, || [0; 12]), Err(VaultError::TooLarge));
+    }
+
+    fn load(id: u16) -> (Bytes, Bytes) {
+        (
+            package(0x4002).add_arg(ARG_UPLOAD).add_arg_u16(ARG_ID, id),
+            answer(0x4002, 0),
+        )
+    }
+
+    // Read of the template in sensor RAM answered with `template`
+    fn read(template: &[u8]) -> (Bytes, Bytes) {
+        (
+            package(0x0006).add_arg(ARG_UPLOAD),
+            answer(0x0006, 0).add_arg_data(ARG_DATA, template),
+        )
+    }
+
+    fn write(template: &[u8]) -> (Bytes, Bytes) {
+        (
+            package(0x0006)
+                .add_arg(ARG_DOWNLOAD)
+                .add_arg_data(ARG_DATA, template),
+            answer(0x0006, 0),
+        )
+    }
+
+    fn delete(id: u16) -> (Bytes, Bytes) {
+        (
+            package(0x4002).add_arg(0x1009).add_arg_u16(ARG_ID, id),
+            answer(0x4002, 0),
+        )
+    }
+
+    fn save(id: u16) -> (Bytes, Bytes) {
+        (
+            package(0x0006).add_arg(0x1008).add_arg_u16(ARG_ID, id),
+            answer(0x0006, 0),
+        )
+    }
+
+    #[test]
+    fn backup_reads_every_template() {
+        let packages = [
+            version_query(b"1.2\0"),
+            template_ids(&[1, 7]),
+            load(1),
+            read(&[0x10, 0x20, 0x30]),
+            load(7),
+            read(&[0x77, 0x78]),
+        ];
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        let expected = Vault {
+            firmware: [b'1', b'.', b'2', 0].to_vec(),
+            entries: [
+                VaultEntry {
+                    id: 1,
+                    template: [0x10, 0x20, 0x30].to_vec(),
+                },
+                VaultEntry {
+                    id: 7,
+                    template: [0x77, 0x78].to_vec(),
+                },
+            ]
+            .to_vec(),
+        };
+        match bm.backup() {
+            Ok(vault) => assert_eq!(vault, expected),
+            Err(_) => panic!("Backup failed"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn restore_writes_missing_on_same_firmware() {
+        let packages = [
+            // Firmware version differs
+            version_query(b"1.3\0"),
+            version_query(b"1.2\0"),
+            // 7 is missing, 1 is unchanged
+            template_ids(&[1]),
+            load(1),
+            read(&[0x10, 0x20, 0x30]),
+            write(&[0x77, 0x78]),
+            save(7),
+        ];
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+        let vault = Vault {
+            firmware: [b'1', b'.', b'2', 0].to_vec(),
+            entries: [
+                VaultEntry {
+                    id: 1,
+                    template: [0x10, 0x20, 0x30].to_vec(),
+                },
+                VaultEntry {
+                    id: 7,
+                    template: [0x77, 0x78].to_vec(),
+                },
+            ]
+            .to_vec(),
+        };
+        match bm.restore(&vault) {
+            Err(Error::Vault(VaultError::Firmware)) => {}
+            _ => panic!("Vault from other firmware restored"),
+        }
+        match bm.restore(&vault) {
+            Ok(diff) => assert_eq!(diff.missing, [7].to_vec()),
+            Err(_) => panic!("Restore failed"),
+        }
+
+        mock_done(bm);
+    }
+
+    #[test]
+    fn restore_uploads_before_deleting_changed() {
+        let vault = Vault {
+            firmware: [b'1', b'.', b'2', 0].to_vec(),
+            entries: [VaultEntry {
+                id: 1,
+                template: [0x10, 0x20, 0x30].to_vec(),
+            }]
+            .to_vec(),
+        };
+        let diff = [
+            template_ids(&[1]),
+            load(1),
+            // Changed on the sensor
+            read(&[0x11]),
+        ];
+        let mut packages = [version_query(b"1.2\0")].to_vec();
+        packages.extend_from_slice(&diff);
+        packages.extend_from_slice(&[write(&[0x10, 0x20, 0x30]), delete(1), save(1)]);
+        packages.push(version_query(b"1.2\0"));
+        packages.extend_from_slice(&diff);
+        // Upload answered without a result, the stored template is kept
+        packages.push((write(&[0x10, 0x20, 0x30]).0, package(0x0006)));
+        let (expectations, irq) = mock_packages(&packages);
+        let mut bm = mock_driver(&expectations, &irq, &[]);
+
+        match bm.restore(&vault) {
+            Ok(diff) => assert_eq!(diff.changed, [1].to_vec()),
+            Err(_) => panic!("Restore failed"),
+        }
+        match bm.restore(&vault) {
+            Err(Error::UnexpectedResponse) => {}
+            _ => panic!("Failed upload not reported"),
+        }
+
+        mock_done(bm);
+    }
+}